@@ -0,0 +1,188 @@
+use axum::{extract::State,
+           http::StatusCode,
+           response::{IntoResponse, Redirect, Response},
+           Form};
+use axum_template::RenderHtml;
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{info, instrument};
+
+use crate::{audit::{self, Actor, AuditAction},
+            csrf::CsrfToken,
+            database::{AppState, RestoreOutcome},
+            error::{error_page, AppResult},
+            permissions::Permissions};
+
+/// the admin overview
+#[instrument(skip(state, permissions, csrf_token))]
+pub async fn index(State(state): State<AppState>,
+                   permissions: Permissions,
+                   csrf_token: CsrfToken)
+                   -> AppResult<Response> {
+    if let Some(denied) = permissions.require_admin(&state) {
+        return Ok(denied);
+    }
+
+    render_index(&state, &permissions, &csrf_token, None).await
+}
+
+pub async fn render_index(state: &AppState,
+                          permissions: &Permissions,
+                          csrf_token: &CsrfToken,
+                          message: Option<String>)
+                          -> AppResult<Response> {
+    let shortlinks = state.get_all_shortlinks().await?;
+    let trashed = state.get_trash().await?.len();
+
+    Ok(RenderHtml("admin",
+                  state.get_engine(),
+                  json!({
+                      "user": permissions.identity(),
+                      "links": shortlinks.len(),
+                      "trashed": trashed,
+                      "csrf_token": csrf_token,
+                      "message": message
+                  })).into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TransferForm {
+    from: String,
+    to: String,
+}
+
+/// hand every link of one owner to another, e.g. when someone leaves
+#[instrument(skip(state, permissions, actor, csrf_token))]
+pub async fn transfer(State(state): State<AppState>,
+                      permissions: Permissions,
+                      actor: Actor,
+                      csrf_token: CsrfToken,
+                      Form(form): Form<TransferForm>)
+                      -> AppResult<Response> {
+    if let Some(denied) = permissions.require_admin(&state) {
+        return Ok(denied);
+    }
+
+    let (from, to) = (form.from.trim(), form.to.trim());
+    if from.is_empty() || to.is_empty() {
+        return Ok(error_page(state.get_engine(),
+                             StatusCode::UNPROCESSABLE_ENTITY,
+                             "Both the current and the new owner are needed".to_string()));
+    }
+
+    let mut transferred = 0;
+    for shortlink in state.get_all_shortlinks().await? {
+        if !shortlink.is_owned_by(from) {
+            continue;
+        }
+
+        let mut updated = shortlink.clone();
+        updated.owner = to.to_string();
+        updated.co_owners
+               .retain(|co_owner| !co_owner.eq_ignore_ascii_case(to));
+        updated.modified = Some(Utc::now());
+        state.store_shortlink(updated.clone()).await?;
+        transferred += 1;
+
+        audit::record(&state,
+                      actor.event(AuditAction::Transfer, Some(&updated.keyword))
+                           .with_changes(Some(&shortlink), Some(&updated))).await;
+    }
+
+    info!(from, to, transferred, "Transferred links");
+
+    render_index(&state,
+                 &permissions,
+                 &csrf_token,
+                 Some(format!("Transferred {transferred} links from {from} to {to}"))).await
+}
+
+/// deleted links waiting to be restored or purged
+#[instrument(skip(state, permissions, csrf_token))]
+pub async fn trash(State(state): State<AppState>,
+                   permissions: Permissions,
+                   csrf_token: CsrfToken)
+                   -> AppResult<Response> {
+    if let Some(denied) = permissions.require_admin(&state) {
+        return Ok(denied);
+    }
+
+    let trash = state.get_trash()
+                     .await?
+                     .into_iter()
+                     .map(|mut trashed| {
+                         trashed.shortlink = trashed.shortlink.redacted();
+                         trashed
+                     })
+                     .collect::<Vec<_>>();
+
+    Ok(RenderHtml("trash",
+                  state.get_engine(),
+                  json!({
+                      "trash": trash,
+                      "csrf_token": csrf_token
+                  })).into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TrashForm {
+    keyword: Option<String>,
+}
+
+#[instrument(skip(state, permissions, actor))]
+pub async fn restore(State(state): State<AppState>,
+                     permissions: Permissions,
+                     actor: Actor,
+                     Form(form): Form<TrashForm>)
+                     -> AppResult<Response> {
+    if let Some(denied) = permissions.require_admin(&state) {
+        return Ok(denied);
+    }
+
+    let keyword = form.keyword.unwrap_or_default();
+
+    Ok(match state.restore_shortlink(&keyword).await? {
+        RestoreOutcome::Restored => {
+            audit::record(&state, actor.event(AuditAction::Restore, Some(&keyword))).await;
+            Redirect::to(&format!("/edit/{keyword}")).into_response()
+        }
+        RestoreOutcome::NotInTrash => error_page(state.get_engine(),
+                                                 StatusCode::NOT_FOUND,
+                                                 format!("{keyword} is not in the trash")),
+        RestoreOutcome::KeywordTaken => {
+            error_page(state.get_engine(),
+                       StatusCode::CONFLICT,
+                       format!("{keyword} has been taken by a new link since it was deleted"))
+        }
+    })
+}
+
+/// delete one trashed link for good, or all of them when no keyword is given
+#[instrument(skip(state, permissions, actor))]
+pub async fn purge(State(state): State<AppState>,
+                   permissions: Permissions,
+                   actor: Actor,
+                   Form(form): Form<TrashForm>)
+                   -> AppResult<Response> {
+    if let Some(denied) = permissions.require_admin(&state) {
+        return Ok(denied);
+    }
+
+    let keywords = match form.keyword {
+        Some(keyword) => vec![keyword],
+        None => state.get_trash()
+                     .await?
+                     .into_iter()
+                     .map(|trashed| trashed.shortlink.keyword)
+                     .collect(),
+    };
+
+    state.purge_trash(&keywords).await?;
+    info!(?keywords, "Purged trashed links");
+    for keyword in &keywords {
+        audit::record(&state, actor.event(AuditAction::Purge, Some(keyword))).await;
+    }
+
+    Ok(Redirect::to("/admin/trash").into_response())
+}
@@ -0,0 +1,520 @@
+use axum::{extract::{rejection::JsonRejection, Path, Query, State},
+           http::{header::{HeaderMap, HOST, LOCATION},
+                  StatusCode},
+           response::{IntoResponse, Response},
+           Json};
+use serde::{Deserialize, Deserializer, Serialize};
+use tracing::{error, instrument};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{audit::{self, Actor, AuditAction},
+            database::AppState,
+            models::{Destination, RedirectKind, Shortlink},
+            permissions::Permissions,
+            rules::Rule,
+            validation::{validate_shortlink, ValidationErrors}};
+
+/// how many links a page holds unless the client asks otherwise
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 500;
+
+/// An error as the API reports it
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    body: ErrorBody,
+}
+
+/// The body of every error response
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorBody {
+    error: ErrorDetail,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorDetail {
+    /// stable and machine readable, e.g. `not_found` or `invalid`
+    #[schema(value_type = String, example = "invalid")]
+    code: &'static str,
+    message: String,
+    /// what's wrong with each field, for invalid links
+    #[schema(value_type = Option<HashMap<String, String>>, example = json!({"url": "A url is required"}))]
+    fields: Option<ValidationErrors>,
+}
+
+pub type ApiResult<T> = Result<T, ApiError>;
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self { status,
+               body: ErrorBody { error: ErrorDetail { code,
+                                                      message: message.into(),
+                                                      fields: None } } }
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "bad_request", message)
+    }
+
+    pub fn not_found(keyword: &str) -> Self {
+        Self::new(StatusCode::NOT_FOUND,
+                  "not_found",
+                  format!("There is no link called {keyword}"))
+    }
+
+    fn invalid(fields: ValidationErrors) -> Self {
+        let mut invalid = Self::new(StatusCode::UNPROCESSABLE_ENTITY,
+                                    "invalid",
+                                    "The link is not valid");
+        invalid.body.error.fields = Some(fields);
+        invalid
+    }
+    /// 401 when signing in could help, 403 when it wouldn't
+    pub fn denied(state: &AppState, permissions: &Permissions, action: &str) -> Self {
+        match permissions.identity() {
+            None if state.get_config().auth_enabled() => {
+                Self::new(StatusCode::UNAUTHORIZED,
+                          "unauthorized",
+                          format!("Sign in or use an API token to {action}"))
+            }
+            Some(identity) => Self::new(StatusCode::FORBIDDEN,
+                                        "forbidden",
+                                        format!("{} isn't allowed to {action}", identity.user)),
+            None => Self::new(StatusCode::FORBIDDEN,
+                              "forbidden",
+                              format!("Not allowed to {action}")),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(self.body)).into_response()
+    }
+}
+
+/// anything unexpected is logged, the client only learns that it happened
+impl<E> From<E> for ApiError where E: Into<anyhow::Error>
+{
+    fn from(value: E) -> Self {
+        let err = value.into();
+        error!("API request failed: {err:#}");
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR,
+                  "internal",
+                  "Something went wrong")
+    }
+}
+
+/// a body that isn't json, or isn't a link, gets a structured error like everything else
+fn body<T>(payload: Result<Json<T>, JsonRejection>) -> ApiResult<T> {
+    let rejection = match payload {
+        Ok(Json(input)) => return Ok(input),
+        Err(rejection) => rejection,
+    };
+
+    let (status, code) = match rejection {
+        JsonRejection::MissingJsonContentType(_) => {
+            (StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_media_type")
+        }
+        JsonRejection::JsonDataError(_) => (StatusCode::UNPROCESSABLE_ENTITY, "invalid_body"),
+        _ => (StatusCode::BAD_REQUEST, "bad_request"),
+    };
+    // the rejection only says that the body was wrong, the first of its causes says where
+    let mut messages = anyhow::Error::from(rejection).chain()
+                                                     .map(ToString::to_string)
+                                                     .collect::<Vec<_>>();
+    messages.dedup();
+    messages.truncate(2);
+
+    Err(ApiError::new(status, code, messages.join(": ")))
+}
+
+/// tells `"field": null` apart from leaving the field out
+fn present<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+    where T: Deserialize<'de>,
+          D: Deserializer<'de>
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+/// The fields of a link a client can set, every one of them optional
+///
+/// Anything a client can't set, like `hits`, is ignored so a link fetched from the API can be
+/// sent back as is
+#[derive(Debug, Default, Deserialize, ToSchema)]
+pub struct LinkInput {
+    /// only read when creating a link, the path names it otherwise
+    keyword: Option<String>,
+    url: Option<String>,
+    owner: Option<String>,
+    description: Option<String>,
+    private: Option<bool>,
+    redirect_kind: Option<RedirectKind>,
+    destinations: Option<Vec<Destination>>,
+    sticky: Option<bool>,
+    rules: Option<Vec<Rule>>,
+    co_owners: Option<Vec<String>>,
+    /// a new passphrase, or null to remove it
+    #[serde(default, deserialize_with = "present")]
+    #[schema(value_type = Option<String>)]
+    password: Option<Option<String>>,
+    /// null removes the limit
+    #[serde(default, deserialize_with = "present")]
+    #[schema(value_type = Option<u64>)]
+    max_uses: Option<Option<u64>>,
+}
+
+/// `input` laid over `base`, whatever it leaves out stays as `base` has it
+///
+/// `previous` is the link as stored, if it already exists
+fn apply(state: &AppState,
+         permissions: &Permissions,
+         request_host: Option<&str>,
+         previous: Option<&Shortlink>,
+         base: Shortlink,
+         input: LinkInput)
+         -> ApiResult<Shortlink> {
+    let create = previous.is_none();
+    let manages = permissions.can_manage(&base);
+    let mut updated = base;
+
+    let handing_over = input.owner
+                            .as_ref()
+                            .is_some_and(|owner| !owner.eq_ignore_ascii_case(&updated.owner));
+    let changing_co_owners = input.co_owners
+                                  .as_ref()
+                                  .is_some_and(|co_owners| *co_owners != updated.co_owners);
+    if !create && !manages && (handing_over || changing_co_owners) {
+        return Err(ApiError::denied(state,
+                                    permissions,
+                                    &format!("change who owns {}",
+                                             updated.keyword)));
+    }
+
+    // with authentication the owner is whoever created the link, same as the edit form
+    updated.owner = match (permissions.identity(), input.owner) {
+        (Some(identity), _) if create || updated.owner.is_empty() => identity.user.clone(),
+        (_, Some(owner)) if !owner.trim().is_empty() => owner.trim().to_string(),
+        // only without auth, anonymous callers can't create or change links otherwise
+        (None, Some(owner)) => owner,
+        _ => updated.owner,
+    };
+    if let Some(co_owners) = input.co_owners {
+        updated.co_owners = co_owners.into_iter()
+                                     .map(|co_owner| co_owner.trim().to_string())
+                                     .filter(|co_owner| !co_owner.is_empty())
+                                     .collect();
+    }
+
+    if let Some(url) = input.url {
+        updated.url = url.trim().to_string();
+    }
+    if let Some(description) = input.description {
+        updated.description = description;
+    }
+    if let Some(private) = input.private {
+        updated.private = private;
+    }
+    if let Some(redirect_kind) = input.redirect_kind {
+        updated.redirect_kind = redirect_kind;
+    }
+    if let Some(mut destinations) = input.destinations {
+        // hits belong to the server, carry them over for destinations that stay
+        for destination in &mut destinations {
+            destination.hits = previous.iter()
+                                       .flat_map(|previous| &previous.destinations)
+                                       .find(|d| d.url == destination.url)
+                                       .map_or(0, |d| d.hits);
+        }
+        updated.destinations = destinations;
+    }
+    if let Some(sticky) = input.sticky {
+        updated.sticky = sticky;
+    }
+    if let Some(rules) = input.rules {
+        updated.rules = rules;
+    }
+
+    let mut errors = ValidationErrors::default();
+    if let Some(max_uses) = input.max_uses {
+        if max_uses == Some(0) {
+            errors.add("max_uses", "Invalid number of uses 0");
+        }
+        updated.max_uses = max_uses;
+    }
+    validate_shortlink(state.get_config(), request_host, &mut updated, &mut errors);
+    if !errors.is_empty() {
+        return Err(ApiError::invalid(errors));
+    }
+
+    match input.password {
+        Some(Some(password)) if !password.is_empty() => updated.set_password(&password)
+                                                               .map_err(anyhow::Error::msg)?,
+        Some(_) => updated.password_hash = None,
+        None => {}
+    }
+
+    Ok(updated)
+}
+
+/// the link, unless it doesn't exist or the user isn't supposed to know it does
+async fn visible_link(state: &AppState,
+                      permissions: &Permissions,
+                      keyword: &str)
+                      -> ApiResult<Shortlink> {
+    state.get_shortlink(keyword)
+         .await?
+         .filter(|shortlink| permissions.can_view(shortlink))
+         .ok_or_else(|| ApiError::not_found(keyword))
+}
+
+fn request_host(headers: &HeaderMap) -> Option<&str> {
+    headers.get(HOST).and_then(|host| host.to_str().ok())
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ListQuery {
+    /// only links owned by this user
+    owner: Option<String>,
+    /// only links whose keyword, url or description contain this
+    q: Option<String>,
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+/// One page of links
+#[derive(Debug, Serialize, ToSchema)]
+pub struct LinkPage {
+    links: Vec<Shortlink>,
+    /// how many links match, over all pages
+    total: usize,
+    offset: usize,
+    limit: usize,
+}
+
+/// links the user can see, sorted by keyword
+#[utoipa::path(get,
+               path = "/api/v1/links",
+               tag = "links",
+               params(ListQuery),
+               responses((status = 200, description = "Links the caller can see", body = LinkPage)))]
+#[instrument(skip(state, permissions))]
+pub async fn list_links(State(state): State<AppState>,
+                        permissions: Permissions,
+                        Query(query): Query<ListQuery>)
+                        -> ApiResult<Json<LinkPage>> {
+    let search = query.q
+                      .as_deref()
+                      .map(str::to_lowercase)
+                      .filter(|search| !search.is_empty());
+
+    // redacted first, so nobody can search for where a protected link goes
+    let mut links =
+        state.get_all_shortlinks()
+             .await?
+             .into_iter()
+             .filter(|shortlink| permissions.can_view(shortlink))
+             .map(|shortlink| permissions.redact(shortlink))
+             .filter(|shortlink| {
+                 query.owner
+                      .as_deref()
+                      .filter(|owner| !owner.is_empty())
+                      .is_none_or(|owner| shortlink.is_owned_by(owner))
+             })
+             .filter(|shortlink| {
+                 search.as_deref().is_none_or(|search| {
+                                      [&shortlink.keyword, &shortlink.url, &shortlink.description]
+                                          .iter()
+                                          .any(|field| field.to_lowercase().contains(search))
+                                  })
+             })
+             .collect::<Vec<_>>();
+    links.sort_by(|a, b| a.keyword.cmp(&b.keyword));
+
+    let total = links.len();
+    let offset = query.offset.unwrap_or(0);
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let links = links.into_iter().skip(offset).take(limit).collect();
+
+    Ok(Json(LinkPage { links,
+                       total,
+                       offset,
+                       limit }))
+}
+
+/// a new link, named by `keyword` in the body
+#[utoipa::path(post,
+               path = "/api/v1/links",
+               tag = "links",
+               request_body = LinkInput,
+               responses((status = 201, description = "The new link", body = Shortlink),
+                         (status = 401, description = "Not signed in", body = ErrorBody),
+                         (status = 403, description = "Not allowed", body = ErrorBody),
+                         (status = 409, description = "The keyword is taken", body = ErrorBody),
+                         (status = 422, description = "The link is not valid", body = ErrorBody)))]
+#[instrument(skip(state, headers, permissions, actor, payload))]
+pub async fn create_link(State(state): State<AppState>,
+                         headers: HeaderMap,
+                         permissions: Permissions,
+                         actor: Actor,
+                         payload: Result<Json<LinkInput>, JsonRejection>)
+                         -> ApiResult<Response> {
+    if !permissions.can_create() {
+        return Err(ApiError::denied(&state, &permissions, "create links"));
+    }
+
+    let mut input = body(payload)?;
+    let keyword = input.keyword.take().unwrap_or_default().trim().to_string();
+    if state.get_shortlink(&keyword).await?.is_some() {
+        return Err(ApiError::new(StatusCode::CONFLICT,
+                                 "conflict",
+                                 format!("A link called {keyword} already exists")));
+    }
+
+    let base = Shortlink { keyword,
+                           ..Default::default() };
+    let created = apply(&state,
+                        &permissions,
+                        request_host(&headers),
+                        None,
+                        base,
+                        input)?;
+    let saved = state.save_shortlink(None, created).await?;
+    audit::record(&state,
+                  actor.event(AuditAction::Create, Some(&saved.keyword))
+                       .with_changes(None, Some(&saved))).await;
+
+    let location = format!("/api/v1/links/{}", saved.keyword);
+    Ok((StatusCode::CREATED, [(LOCATION, location)], Json(saved.redacted())).into_response())
+}
+
+#[utoipa::path(get,
+               path = "/api/v1/links/{keyword}",
+               tag = "links",
+               params(("keyword" = String, Path, description = "the shortlink's keyword")),
+               responses((status = 200, description = "The link", body = Shortlink),
+                         (status = 404, description = "No such link", body = ErrorBody)))]
+#[instrument(skip(state, permissions))]
+pub async fn get_link(State(state): State<AppState>,
+                      Path(keyword): Path<String>,
+                      permissions: Permissions)
+                      -> ApiResult<Json<Shortlink>> {
+    let shortlink = visible_link(&state, &permissions, &keyword).await?;
+    Ok(Json(permissions.redact(shortlink)))
+}
+
+/// change an existing link, `replace` starting over from an empty one instead of laying the
+/// input over what's there
+async fn change_link(state: AppState,
+                     keyword: String,
+                     headers: HeaderMap,
+                     permissions: Permissions,
+                     actor: Actor,
+                     payload: Result<Json<LinkInput>, JsonRejection>,
+                     replace: bool)
+                     -> ApiResult<Json<Shortlink>> {
+    let existing = visible_link(&state, &permissions, &keyword).await?;
+    if !permissions.can_edit(&existing) {
+        return Err(ApiError::denied(&state,
+                                    &permissions,
+                                    &format!("change {keyword}")));
+    }
+
+    let input = body(payload)?;
+    // hits, ownership and the password aren't part of what a link says, they survive a replace
+    let base = match replace {
+        true => Shortlink { keyword: existing.keyword.clone(),
+                            owner: existing.owner.clone(),
+                            co_owners: existing.co_owners.clone(),
+                            hits: existing.hits,
+                            password_hash: existing.password_hash.clone(),
+                            ..Default::default() },
+        false => existing.clone(),
+    };
+    let updated = apply(&state,
+                        &permissions,
+                        request_host(&headers),
+                        Some(&existing),
+                        base,
+                        input)?;
+
+    let saved = state.save_shortlink(Some(&existing), updated).await?;
+    audit::record(&state,
+                  actor.event(AuditAction::Update, Some(&keyword))
+                       .with_changes(Some(&existing), Some(&saved))).await;
+
+    Ok(Json(saved.redacted()))
+}
+
+/// replace a link, fields left out are reset
+#[utoipa::path(put,
+               path = "/api/v1/links/{keyword}",
+               tag = "links",
+               params(("keyword" = String, Path, description = "the shortlink's keyword")),
+               request_body = LinkInput,
+               responses((status = 200, description = "The changed link", body = Shortlink),
+                         (status = 403, description = "Not allowed", body = ErrorBody),
+                         (status = 404, description = "No such link", body = ErrorBody),
+                         (status = 422, description = "The link is not valid", body = ErrorBody)))]
+#[instrument(skip(state, headers, permissions, actor, payload))]
+pub async fn replace_link(State(state): State<AppState>,
+                          Path(keyword): Path<String>,
+                          headers: HeaderMap,
+                          permissions: Permissions,
+                          actor: Actor,
+                          payload: Result<Json<LinkInput>, JsonRejection>)
+                          -> ApiResult<Json<Shortlink>> {
+    change_link(state, keyword, headers, permissions, actor, payload, true).await
+}
+
+/// change some fields of a link, fields left out are kept
+#[utoipa::path(patch,
+               path = "/api/v1/links/{keyword}",
+               tag = "links",
+               params(("keyword" = String, Path, description = "the shortlink's keyword")),
+               request_body = LinkInput,
+               responses((status = 200, description = "The changed link", body = Shortlink),
+                         (status = 403, description = "Not allowed", body = ErrorBody),
+                         (status = 404, description = "No such link", body = ErrorBody),
+                         (status = 422, description = "The link is not valid", body = ErrorBody)))]
+#[instrument(skip(state, headers, permissions, actor, payload))]
+pub async fn update_link(State(state): State<AppState>,
+                         Path(keyword): Path<String>,
+                         headers: HeaderMap,
+                         permissions: Permissions,
+                         actor: Actor,
+                         payload: Result<Json<LinkInput>, JsonRejection>)
+                         -> ApiResult<Json<Shortlink>> {
+    change_link(state, keyword, headers, permissions, actor, payload, false).await
+}
+
+/// move a link to the trash
+#[utoipa::path(delete,
+               path = "/api/v1/links/{keyword}",
+               tag = "links",
+               params(("keyword" = String, Path, description = "the shortlink's keyword")),
+               responses((status = 204, description = "Moved to the trash"),
+                         (status = 403, description = "Not allowed", body = ErrorBody),
+                         (status = 404, description = "No such link", body = ErrorBody)))]
+#[instrument(skip(state, permissions, actor))]
+pub async fn delete_link(State(state): State<AppState>,
+                         Path(keyword): Path<String>,
+                         permissions: Permissions,
+                         actor: Actor)
+                         -> ApiResult<StatusCode> {
+    let shortlink = visible_link(&state, &permissions, &keyword).await?;
+    if !permissions.can_manage(&shortlink) {
+        return Err(ApiError::denied(&state,
+                                    &permissions,
+                                    &format!("delete {keyword}")));
+    }
+
+    let event = actor.event(AuditAction::Delete, Some(&keyword))
+                     .with_changes(Some(&shortlink), None);
+    let deleted_by = permissions.identity().map(|identity| identity.user.clone());
+    state.trash_shortlink(shortlink, deleted_by).await?;
+    audit::record(&state, event).await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
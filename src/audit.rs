@@ -0,0 +1,359 @@
+use axum::{async_trait,
+           extract::{ConnectInfo, FromRequestParts, Query, State},
+           http::request::Parts,
+           response::{IntoResponse, Response},
+           Json};
+use axum_template::RenderHtml;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{collections::BTreeMap, convert::Infallible, net::SocketAddr};
+use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+use tracing::{error, instrument};
+
+use crate::{auth::{client_ip, CurrentUser},
+            database::{AppState, DatabaseError},
+            error::AppResult,
+            formats::parse_timestamp,
+            models::Shortlink,
+            permissions::Permissions};
+
+/// the redis list holding every event, newest first
+const AUDIT_KEY: &str = "audit";
+/// how many events are read from redis at a time while filtering
+const PAGE_SIZE: isize = 500;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Create,
+    Update,
+    Delete,
+    Transfer,
+    Restore,
+    Purge,
+    CreateToken,
+    RevokeToken,
+    ExportBackup,
+    RestoreBackup,
+    MigrateLegacy,
+    RewriteRecords,
+}
+
+/// How one field of a link changed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Change {
+    pub before: Value,
+    pub after: Value,
+}
+
+/// Something someone changed, recorded once and never touched again
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditEvent {
+    pub timestamp: DateTime<Utc>,
+    /// the signed in user, if any
+    pub actor: Option<String>,
+    pub ip: String,
+    pub action: AuditAction,
+    pub keyword: Option<String>,
+    /// before and after of every field that changed
+    #[serde(default)]
+    pub changes: BTreeMap<String, Change>,
+    /// anything else worth knowing that isn't a link field
+    pub detail: Option<String>,
+}
+
+impl AuditEvent {
+    pub fn with_changes(mut self, before: Option<&Shortlink>, after: Option<&Shortlink>) -> Self {
+        self.changes = diff(before, after);
+        self
+    }
+
+    pub fn with_detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+}
+
+/// Who is doing something, to put in the audit log
+#[derive(Debug, Clone)]
+pub struct Actor {
+    user: Option<String>,
+    ip: String,
+}
+
+impl Actor {
+    /// commands run on the server itself, like `linkshrink restore`
+    pub fn cli() -> Self {
+        Self { user: Some("cli".to_string()),
+               ip: "local".to_string() }
+    }
+
+    pub fn event(&self, action: AuditAction, keyword: Option<&str>) -> AuditEvent {
+        AuditEvent { timestamp: Utc::now(),
+                     actor: self.user.clone(),
+                     ip: self.ip.clone(),
+                     action,
+                     keyword: keyword.map(str::to_string),
+                     changes: BTreeMap::new(),
+                     detail: None }
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for Actor {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts,
+                                state: &AppState)
+                                -> Result<Self, Self::Rejection> {
+        let user = CurrentUser::from_request_parts(parts, state).await?
+                                                                .0
+                                                                .map(|identity| identity.user);
+        let ip = match parts.extensions.get::<ConnectInfo<SocketAddr>>() {
+            Some(ConnectInfo(peer)) => {
+                client_ip(state.get_config(), *peer, &parts.headers).to_string()
+            }
+            None => "unknown".to_string(),
+        };
+
+        Ok(Self { user, ip })
+    }
+}
+
+/// a link as far as the audit log cares, hits and timestamps change all the time
+fn snapshot(shortlink: Option<&Shortlink>) -> BTreeMap<String, Value> {
+    let Some(Value::Object(fields)) =
+        shortlink.map(|shortlink| json!(shortlink.clone().redacted()))
+    else {
+        return BTreeMap::new();
+    };
+
+    fields.into_iter()
+          .filter(|(field, _)| field != "hits" && field != "modified")
+          .collect()
+}
+
+fn diff(before: Option<&Shortlink>, after: Option<&Shortlink>) -> BTreeMap<String, Change> {
+    let (old, new) = (snapshot(before), snapshot(after));
+
+    let field = |fields: &BTreeMap<String, Value>, name: &str| {
+        fields.get(name).cloned().unwrap_or(Value::Null)
+    };
+
+    let mut changes = old.keys()
+                         .chain(new.keys())
+                         .map(|name| {
+                             (name.clone(),
+                              Change { before: field(&old, name),
+                                       after: field(&new, name) })
+                         })
+                         .filter(|(_, change)| change.before != change.after)
+                         .collect::<BTreeMap<_, _>>();
+
+    // both hashes are redacted, but we still want to know the password was changed
+    let hashes = (before.and_then(|link| link.password_hash.as_ref()),
+                  after.and_then(|link| link.password_hash.as_ref()));
+    if let (Some(old_hash), Some(new_hash)) = hashes {
+        if old_hash != new_hash {
+            changes.insert("password_hash".to_string(),
+                           Change { before: json!("redacted"),
+                                    after: json!("changed") });
+        }
+    }
+
+    changes
+}
+
+/// append an event to the log, and to the file sink if there is one
+///
+/// a failure is logged rather than failing the change that was already made
+pub async fn record(state: &AppState, event: AuditEvent) {
+    let line = match serde_json::to_string(&event) {
+        Ok(line) => line,
+        Err(err) => {
+            error!(?event, "Failed to serialize audit event: {err}");
+            return;
+        }
+    };
+
+    if let Err(err) = state.push_to_list(AUDIT_KEY, &line).await {
+        error!(line, "Failed to store audit event: {err}");
+    }
+
+    if let Some(path) = &state.get_config().audit_log_file {
+        let written = async {
+            let mut file = OpenOptions::new().create(true)
+                                             .append(true)
+                                             .open(path)
+                                             .await?;
+            file.write_all(format!("{line}\n").as_bytes()).await
+        };
+
+        if let Err(err) = written.await {
+            error!(line, ?path, "Failed to write audit event to file: {err}");
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditQuery {
+    actor: Option<String>,
+    keyword: Option<String>,
+    action: Option<String>,
+    /// `YYYY-MM-DD` or RFC 3339
+    since: Option<String>,
+    until: Option<String>,
+    limit: Option<usize>,
+}
+
+fn parse_time(value: &str, end_of_day: bool) -> Option<DateTime<Utc>> {
+    if let Some(time) = parse_timestamp(value) {
+        return Some(time);
+    }
+
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?;
+    let time = match end_of_day {
+        true => date.and_hms_opt(23, 59, 59)?,
+        false => date.and_hms_opt(0, 0, 0)?,
+    };
+    Some(time.and_utc())
+}
+
+/// empty form fields don't filter anything
+fn filled(filter: &Option<String>) -> Option<&str> {
+    filter.as_deref().filter(|value| !value.is_empty())
+}
+
+impl AuditQuery {
+    fn matches(&self, event: &AuditEvent) -> bool {
+        let since = self.since
+                        .as_deref()
+                        .and_then(|since| parse_time(since, false));
+        let until = self.until
+                        .as_deref()
+                        .and_then(|until| parse_time(until, true));
+
+        filled(&self.actor).is_none_or(|actor| {
+                               event.actor
+                                    .as_deref()
+                                    .is_some_and(|who| who.eq_ignore_ascii_case(actor))
+                           })
+        && filled(&self.keyword).is_none_or(|keyword| event.keyword.as_deref() == Some(keyword))
+        && filled(&self.action).is_none_or(|action| json!(event.action) == action)
+        && since.is_none_or(|since| event.timestamp >= since)
+        && until.is_none_or(|until| event.timestamp <= until)
+    }
+}
+
+/// every event, newest first, for backups
+pub async fn all_events(state: &AppState) -> Result<Vec<AuditEvent>, DatabaseError> {
+    Ok(state.list_range(AUDIT_KEY, 0, -1)
+            .await?
+            .into_iter()
+            .filter_map(|line| {
+                serde_json::from_str(&line).map_err(|err| {
+                                               error!(line,
+                                                      "Failed to deserialize audit event: {err}")
+                                           })
+                                           .ok()
+            })
+            .collect())
+}
+
+/// put back events from a backup, newest first, unless the log already has history of its own
+pub async fn restore_events(state: &AppState,
+                            events: &[AuditEvent])
+                            -> Result<bool, DatabaseError> {
+    if !state.list_range(AUDIT_KEY, 0, 0).await?.is_empty() {
+        return Ok(false);
+    }
+
+    let lines = events.iter()
+                      .filter_map(|event| serde_json::to_string(event).ok())
+                      .collect::<Vec<_>>();
+    state.append_to_list(AUDIT_KEY, &lines).await?;
+
+    Ok(true)
+}
+
+/// the newest events matching the query
+async fn find(state: &AppState, query: &AuditQuery) -> AppResult<Vec<AuditEvent>> {
+    let limit = query.limit.unwrap_or(100).clamp(1, 1000);
+    let mut events = vec![];
+
+    let mut start = 0;
+    loop {
+        let page = state.list_range(AUDIT_KEY, start, start + PAGE_SIZE - 1)
+                        .await?;
+        let exhausted = (page.len() as isize) < PAGE_SIZE;
+
+        for line in page {
+            match serde_json::from_str::<AuditEvent>(&line) {
+                Ok(event) if query.matches(&event) => events.push(event),
+                Ok(_) => {}
+                Err(err) => error!(line, "Failed to deserialize audit event: {err}"),
+            }
+            if events.len() == limit {
+                return Ok(events);
+            }
+        }
+
+        if exhausted {
+            return Ok(events);
+        }
+        start += PAGE_SIZE;
+    }
+}
+
+/// the audit log, for admins
+#[instrument(skip(state, permissions))]
+pub async fn page(State(state): State<AppState>,
+                  permissions: Permissions,
+                  Query(query): Query<AuditQuery>)
+                  -> AppResult<Response> {
+    if let Some(denied) = permissions.require_admin(&state) {
+        return Ok(denied);
+    }
+
+    // values are json, show them that way
+    let events = find(&state, &query).await?
+                                     .into_iter()
+                                     .map(|event| {
+                                         let changes = event.changes
+                                                            .iter()
+                                                            .map(|(field, change)| {
+                                                                format!("{field}: {} → {}",
+                                                                        change.before, change.after)
+                                                            })
+                                                            .collect::<Vec<_>>();
+                                         json!({ "event": event, "changes": changes })
+                                     })
+                                     .collect::<Vec<_>>();
+
+    Ok(RenderHtml("audit",
+                  state.get_engine(),
+                  json!({
+                      "events": events,
+                      "query": {
+                          "actor": query.actor,
+                          "keyword": query.keyword,
+                          "action": query.action,
+                          "since": query.since,
+                          "until": query.until
+                      }
+                  })).into_response())
+}
+
+/// the audit log as json, takes the same filters as the page
+#[instrument(skip(state, permissions))]
+pub async fn events(State(state): State<AppState>,
+                    permissions: Permissions,
+                    Query(query): Query<AuditQuery>)
+                    -> AppResult<Response> {
+    if let Some(denied) = permissions.require_admin(&state) {
+        return Ok(denied);
+    }
+
+    Ok(Json(find(&state, &query).await?).into_response())
+}
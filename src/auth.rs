@@ -0,0 +1,142 @@
+use axum::{async_trait,
+           extract::{ConnectInfo, FromRequestParts, State},
+           http::{header::WWW_AUTHENTICATE, request::Parts, HeaderMap, Request, StatusCode},
+           middleware::Next,
+           response::{IntoResponse, Response}};
+use serde::{Deserialize, Serialize};
+use std::{convert::Infallible,
+          net::{IpAddr, SocketAddr}};
+use tracing::{debug, warn};
+
+use crate::{config::Config,
+            database::AppState,
+            oidc,
+            tokens::{self, Bearer}};
+
+/// Someone we know the name of
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Identity {
+    pub user: String,
+    pub email: Option<String>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+/// The identity attached to the current request by [`authenticate`], if any
+#[derive(Debug, Clone, Default)]
+pub struct CurrentUser(pub Option<Identity>);
+
+impl CurrentUser {
+    pub fn identity(&self) -> Option<&Identity> {
+        self.0.as_ref()
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for CurrentUser where S: Send + Sync
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts.extensions
+                .get::<CurrentUser>()
+                .cloned()
+                .unwrap_or_default())
+    }
+}
+
+/// work out who is making the request and attach it as a [`CurrentUser`], along with the
+/// [`TokenScope`](tokens::TokenScope) when that is an API token
+pub async fn authenticate<B>(State(state): State<AppState>,
+                             ConnectInfo(peer): ConnectInfo<SocketAddr>,
+                             mut request: Request<B>,
+                             next: Next<B>)
+                             -> Response {
+    // an API token is asked for explicitly, so it wins over whatever else the request carries
+    match tokens::bearer_token(&state, request.headers()).await {
+        Bearer::Valid(token) => {
+            debug!(user = token.user, scope = ?token.scope, "Authenticated API token");
+            request.extensions_mut().insert(token.scope);
+            request.extensions_mut()
+                   .insert(CurrentUser(Some(token.identity())));
+            return next.run(request).await;
+        }
+        Bearer::Invalid => {
+            return (StatusCode::UNAUTHORIZED,
+                    [(WWW_AUTHENTICATE, r#"Bearer error="invalid_token""#)],
+                    "Unknown or revoked API token")
+                                                   .into_response();
+        }
+        Bearer::Missing => {}
+    }
+
+    let identity = match from_proxy_headers(state.get_config(), peer, request.headers()) {
+        Some(identity) => Some(identity),
+        None => oidc::session_identity(&state, request.headers()).await,
+    };
+
+    debug!(?identity, "Authenticated request");
+    request.extensions_mut().insert(CurrentUser(identity));
+
+    next.run(request).await
+}
+
+/// the SSO proxy in front of us tells us who the user is, but only it may do so
+fn from_proxy_headers(config: &Config, peer: SocketAddr, headers: &HeaderMap) -> Option<Identity> {
+    let header = |name: &str| {
+        headers.get(name)
+               .and_then(|value| value.to_str().ok())
+               .map(str::trim)
+               .filter(|value| !value.is_empty())
+               .map(str::to_string)
+    };
+
+    let user = config.auth_user_header.as_deref().and_then(header);
+    let email = config.auth_email_header.as_deref().and_then(header);
+
+    if !is_trusted_proxy(config, peer) {
+        if user.is_some() || email.is_some() {
+            warn!(%peer, "Ignoring identity headers from an untrusted address");
+        }
+        return None;
+    }
+
+    // fall back to the email when the proxy only sends that
+    let user = user.or_else(|| email.clone())?;
+    let groups = config.auth_groups_header
+                       .as_deref()
+                       .and_then(header)
+                       .map(|groups| {
+                           groups.split(',')
+                                 .map(str::trim)
+                                 .filter(|group| !group.is_empty())
+                                 .map(str::to_string)
+                                 .collect()
+                       })
+                       .unwrap_or_default();
+
+    Some(Identity { user,
+                    email,
+                    groups })
+}
+
+fn is_trusted_proxy(config: &Config, peer: SocketAddr) -> bool {
+    config.trusted_proxies
+          .iter()
+          .any(|proxy| proxy.contains(&peer.ip()))
+}
+
+/// the address of the client, as reported by our reverse proxy if it is a trusted one
+pub fn client_ip(config: &Config, peer: SocketAddr, headers: &HeaderMap) -> IpAddr {
+    if !is_trusted_proxy(config, peer) {
+        return peer.ip();
+    }
+
+    // the left-most X-Forwarded-For entry is the original client
+    headers.get("X-Real-IP")
+           .or_else(|| headers.get("X-Forwarded-For"))
+           .and_then(|ip| ip.to_str().ok())
+           .and_then(|ip| ip.split(',').next())
+           .and_then(|ip| ip.trim().parse().ok())
+           .unwrap_or_else(|| peer.ip())
+}
@@ -0,0 +1,256 @@
+use axum::{extract::State,
+           http::{header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+                  StatusCode},
+           response::{IntoResponse, Response},
+           Form, Json};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{info, instrument};
+
+use crate::{admin,
+            api::{ApiError, ApiResult},
+            audit::{self, Actor, AuditAction, AuditEvent},
+            csrf::CsrfToken,
+            database::{AppState, DatabaseError, BATCH_SIZE},
+            error::{error_page, AppResult},
+            models::{Shortlink, TrashedShortlink},
+            permissions::Permissions};
+
+/// what the header line calls the file, anything else isn't a backup of ours
+const BACKUP_FORMAT: &str = "linkshrink-backup";
+/// bumped whenever the meaning of a line changes, older versions can still be restored
+pub const BACKUP_VERSION: u32 = 1;
+/// backups are much bigger than any form
+pub const BACKUP_BODY_LIMIT: usize = 256 * 1024 * 1024;
+
+/// One line of a backup, which is JSON Lines starting with a [`BackupLine::Header`]
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BackupLine {
+    Header {
+        format: String,
+        version: u32,
+        created: DateTime<Utc>,
+    },
+    /// everything about a link, including its hits and password hash
+    Shortlink(Shortlink),
+    /// how often a link with `max_uses` has been used
+    Uses {
+        keyword: String,
+        uses: u64,
+    },
+    Trashed(TrashedShortlink),
+    /// the audit log, newest first
+    Audit(AuditEvent),
+}
+
+/// What restoring a backup did
+#[derive(Serialize, Debug, Default)]
+pub struct RestoreReport {
+    version: u32,
+    shortlinks: usize,
+    uses: usize,
+    trashed: usize,
+    audit_events: usize,
+    /// the audit log is only restored into an empty one, so history is never doubled
+    audit_skipped: bool,
+    /// lines that couldn't be read, and why
+    errors: Vec<String>,
+}
+
+#[derive(Debug, Error)]
+pub enum RestoreError {
+    #[error("{0}")]
+    InvalidBackup(String),
+    #[error(transparent)]
+    Database(#[from] DatabaseError),
+}
+
+/// everything in the store as a backup file
+pub async fn export(state: &AppState) -> Result<String, DatabaseError> {
+    let mut lines = vec![BackupLine::Header { format: BACKUP_FORMAT.to_string(),
+                                              version: BACKUP_VERSION,
+                                              created: Utc::now() }];
+
+    let mut shortlinks = state.get_all_shortlinks().await?;
+    shortlinks.sort_by(|a, b| a.keyword.cmp(&b.keyword));
+    for shortlink in shortlinks {
+        if shortlink.max_uses.is_some() {
+            lines.push(BackupLine::Uses { keyword: shortlink.keyword.clone(),
+                                          uses: state.get_uses(&shortlink.keyword).await? });
+        }
+        lines.push(BackupLine::Shortlink(shortlink));
+    }
+    lines.extend(state.get_trash()
+                      .await?
+                      .into_iter()
+                      .map(BackupLine::Trashed));
+    lines.extend(audit::all_events(state).await?
+                                         .into_iter()
+                                         .map(BackupLine::Audit));
+
+    let mut file = String::new();
+    for line in lines {
+        file.push_str(&serde_json::to_string(&line).map_err(|_| {
+                                                       DatabaseError::FailedToQueryRedis
+                                                   })?);
+        file.push('\n');
+    }
+
+    Ok(file)
+}
+
+/// replay a backup into the store, links in it replace links with the same keyword and the rest
+/// are left alone
+pub async fn restore(state: &AppState, file: &str) -> Result<RestoreReport, RestoreError> {
+    let mut lines = file.lines()
+                        .enumerate()
+                        .filter(|(_, line)| !line.trim().is_empty());
+
+    let mut report = RestoreReport::default();
+    match lines.next()
+               .and_then(|(_, line)| serde_json::from_str::<BackupLine>(line).ok())
+    {
+        Some(BackupLine::Header { format, version, .. }) if format == BACKUP_FORMAT => {
+            if version > BACKUP_VERSION {
+                return Err(RestoreError::InvalidBackup(format!("This backup is version {version}, this linkshrink only knows up to version {BACKUP_VERSION}")));
+            }
+            report.version = version;
+        }
+        _ => {
+            return Err(RestoreError::InvalidBackup("This isn't a linkshrink backup, the first line has to be its header".to_string()))
+        }
+    }
+
+    let mut shortlinks = vec![];
+    let mut events = vec![];
+    for (index, line) in lines {
+        match serde_json::from_str::<BackupLine>(line) {
+            Ok(BackupLine::Shortlink(shortlink)) => shortlinks.push(shortlink),
+            Ok(BackupLine::Uses { keyword, uses }) => {
+                state.set_uses(&keyword, uses).await?;
+                report.uses += 1;
+            }
+            Ok(BackupLine::Trashed(trashed)) => {
+                state.store_trashed(&trashed).await?;
+                report.trashed += 1;
+            }
+            Ok(BackupLine::Audit(event)) => events.push(event),
+            Ok(BackupLine::Header { .. }) => {
+                report.errors
+                      .push(format!("line {}: a second header", index + 1))
+            }
+            Err(err) => report.errors.push(format!("line {}: {err}", index + 1)),
+        }
+    }
+
+    for batch in shortlinks.chunks(BATCH_SIZE) {
+        state.put_shortlinks(batch).await?;
+        report.shortlinks += batch.len();
+    }
+
+    if !events.is_empty() {
+        match audit::restore_events(state, &events).await? {
+            true => report.audit_events = events.len(),
+            false => report.audit_skipped = true,
+        }
+    }
+
+    Ok(report)
+}
+
+/// download a backup of everything
+#[instrument(skip(state, permissions, actor))]
+pub async fn download(State(state): State<AppState>,
+                      permissions: Permissions,
+                      actor: Actor)
+                      -> AppResult<Response> {
+    if let Some(denied) = permissions.require_admin(&state) {
+        return Ok(denied);
+    }
+
+    let file = export(&state).await?;
+    audit::record(&state, actor.event(AuditAction::ExportBackup, None)).await;
+
+    let disposition = format!("attachment; filename=\"linkshrink-{}.jsonl\"",
+                              Utc::now().format("%Y-%m-%d"));
+    Ok(([(CONTENT_TYPE, "application/x-ndjson".to_string()),
+         (CONTENT_DISPOSITION, disposition)],
+        file)
+             .into_response())
+}
+
+pub async fn restore_and_record(state: &AppState,
+                                actor: &Actor,
+                                file: &str)
+                                -> Result<RestoreReport, RestoreError> {
+    let report = restore(state, file).await?;
+    info!(?report, "Restored a backup");
+    audit::record(state,
+                  actor.event(AuditAction::RestoreBackup, None)
+                       .with_detail(format!("{} links, {} trashed",
+                                            report.shortlinks, report.trashed))).await;
+
+    Ok(report)
+}
+
+/// restore a backup sent as the body, for scripts
+#[instrument(skip(state, permissions, actor, file))]
+pub async fn upload(State(state): State<AppState>,
+                    permissions: Permissions,
+                    actor: Actor,
+                    file: String)
+                    -> ApiResult<Json<RestoreReport>> {
+    if !permissions.is_admin() {
+        return Err(ApiError::denied(&state, &permissions, "restore backups"));
+    }
+
+    match restore_and_record(&state, &actor, &file).await {
+        Ok(report) => Ok(Json(report)),
+        Err(RestoreError::InvalidBackup(err)) => Err(ApiError::bad_request(err)),
+        Err(RestoreError::Database(err)) => Err(err.into()),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RestoreForm {
+    data: String,
+}
+
+/// restore a backup pasted or loaded into the admin page
+#[instrument(skip(state, permissions, actor, csrf_token, form))]
+pub async fn restore_form(State(state): State<AppState>,
+                          permissions: Permissions,
+                          actor: Actor,
+                          csrf_token: CsrfToken,
+                          Form(form): Form<RestoreForm>)
+                          -> AppResult<Response> {
+    if let Some(denied) = permissions.require_admin(&state) {
+        return Ok(denied);
+    }
+
+    let report = match restore_and_record(&state, &actor, &form.data).await {
+        Ok(report) => report,
+        Err(RestoreError::InvalidBackup(err)) => {
+            return Ok(error_page(state.get_engine(),
+                                 StatusCode::UNPROCESSABLE_ENTITY,
+                                 err))
+        }
+        Err(RestoreError::Database(err)) => return Err(err.into()),
+    };
+
+    let mut message = format!("Restored {} links, {} use counts and {} trashed links",
+                              report.shortlinks, report.uses, report.trashed);
+    match report.audit_skipped {
+        true => {
+            message.push_str(". The audit log already had history of its own, so it was left alone")
+        }
+        false => message.push_str(&format!(" and {} audit events", report.audit_events)),
+    }
+    if !report.errors.is_empty() {
+        message.push_str(&format!(". Skipped unreadable lines: {}", report.errors.join("; ")));
+    }
+
+    admin::render_index(&state, &permissions, &csrf_token, Some(message)).await
+}
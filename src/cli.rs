@@ -0,0 +1,100 @@
+use anyhow::{anyhow, Context};
+use tracing::info;
+
+use crate::{audit::{self, Actor, AuditAction},
+            backup,
+            config::Config,
+            database::AppState,
+            legacy, schema};
+
+const USAGE: &str = "usage: linkshrink [serve]
+       linkshrink export FILE    write a backup of everything to FILE
+       linkshrink restore FILE   replay a backup into the store
+       linkshrink migrate-legacy [--dry-run]
+                                 move links the first linkshrink stored under bare keywords
+       linkshrink check-records [--rewrite]
+                                 report stored links that can't be read, and write back
+                                 ones stored at an older schema version";
+
+/// run a maintenance command against the configured store instead of serving, returning whether
+/// there was one, a failing command exits the process
+pub async fn run(config: Config, args: &[String]) -> bool {
+    let (command, args) = match args.split_first() {
+        None => return false,
+        Some((command, _)) if command == "serve" => return false,
+        Some((command, args)) => (command.as_str(), args),
+    };
+
+    if let Err(err) = command_result(config, command, args).await {
+        eprintln!("{err:#}");
+        std::process::exit(1);
+    }
+
+    true
+}
+
+async fn command_result(config: Config, command: &str, args: &[String]) -> anyhow::Result<()> {
+    let file = || {
+        args.first()
+            .cloned()
+            .ok_or_else(|| anyhow!("{command} needs a file\n{USAGE}"))
+    };
+
+    match command {
+        "export" => {
+            let path = file()?;
+            let state = AppState::new(config).await?;
+            let backup = backup::export(&state).await?;
+            tokio::fs::write(&path, backup).await
+                                           .with_context(|| format!("Failed to write {path}"))?;
+            info!(path, "Exported a backup");
+            audit::record(&state,
+                          Actor::cli().event(AuditAction::ExportBackup, None)
+                                      .with_detail(path)).await;
+        }
+        "restore" => {
+            let path = file()?;
+            let backup =
+                tokio::fs::read_to_string(&path).await.with_context(|| {
+                                                           format!("Failed to read {path}")
+                                                       })?;
+            let state = AppState::new(config).await?;
+            let report = backup::restore_and_record(&state, &Actor::cli(), &backup).await?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
+        "migrate-legacy" => {
+            let dry_run = match args.first().map(String::as_str) {
+                None => false,
+                Some("--dry-run") => true,
+                Some(arg) => return Err(anyhow!("Unknown argument {arg}\n{USAGE}")),
+            };
+            let state = AppState::new(config).await?;
+            let report = legacy::migrate(&state, dry_run).await?;
+            if !dry_run {
+                audit::record(&state,
+                              Actor::cli().event(AuditAction::MigrateLegacy, None)
+                                          .with_detail(report.summary())).await;
+            }
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
+        "check-records" => {
+            let rewrite = match args.first().map(String::as_str) {
+                None => config.rewrite_old_records,
+                Some("--rewrite") => true,
+                Some(arg) => return Err(anyhow!("Unknown argument {arg}\n{USAGE}")),
+            };
+            let state = AppState::new(config).await?;
+            let report = schema::check(&state, rewrite).await?;
+            if report.rewritten() > 0 {
+                audit::record(&state,
+                              Actor::cli().event(AuditAction::RewriteRecords, None)
+                                          .with_detail(format!("{} links", report.rewritten()))).await;
+            }
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
+        "help" | "--help" | "-h" => println!("{USAGE}"),
+        _ => return Err(anyhow!("Unknown command {command}\n{USAGE}")),
+    }
+
+    Ok(())
+}
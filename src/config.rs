@@ -0,0 +1,236 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use ipnet::IpNet;
+use rand::{rngs::OsRng, RngCore};
+use regex::{Regex, RegexBuilder};
+use std::{fmt, net::IpAddr, path::PathBuf, str::FromStr, time::Duration};
+
+/// Runtime settings, read from `LINKSHRINK_*` environment variables
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub redis_host: String,
+    pub redis_port: u16,
+    pub listen_host: String,
+    pub listen_port: u16,
+    /// url schemes a shortlink may point at
+    pub allowed_schemes: Vec<String>,
+    /// hostnames linkshrink is served under, links back to these would redirect forever
+    pub public_hosts: Vec<String>,
+    /// keywords that can't be used, on top of the ones taken by our own routes
+    pub reserved_keywords: Vec<String>,
+    /// keywords matching any of these can't be used
+    pub forbidden_keyword_patterns: Vec<Regex>,
+    /// when set, shortlinks may only point at these domains, `*.example.com` also matching subdomains
+    pub allowed_domains: Vec<String>,
+    /// shortlinks may never point at these domains, `*.example.com` also matching subdomains
+    pub denied_domains: Vec<String>,
+    /// header the SSO proxy puts the user name in, e.g. `X-Forwarded-User`
+    pub auth_user_header: Option<String>,
+    /// header the SSO proxy puts the user's email in, e.g. `X-Forwarded-Email`
+    pub auth_email_header: Option<String>,
+    /// header the SSO proxy puts the user's comma separated groups in, e.g. `X-Forwarded-Groups`
+    pub auth_groups_header: Option<String>,
+    /// addresses allowed to set the auth headers, anyone else could pretend to be anybody
+    pub trusted_proxies: Vec<IpNet>,
+    /// users who may do anything
+    pub admin_users: Vec<String>,
+    /// members of these groups may do anything
+    pub admin_groups: Vec<String>,
+    /// built-in login, for deployments without an SSO proxy
+    pub oidc: Option<OidcConfig>,
+    /// how long a login lasts
+    pub session_ttl: Duration,
+    /// only signed in users may follow private links
+    pub private_links_require_auth: bool,
+    /// key for signing the CSRF tokens in our forms
+    pub secret: Secret,
+    /// requests following or looking at links, per client and window, 0 for no limit
+    pub rate_limit_reads: u64,
+    /// requests changing anything, per client and window, 0 for no limit
+    pub rate_limit_writes: u64,
+    pub rate_limit_window: Duration,
+    /// every audit event is also appended here as a line of json
+    pub audit_log_file: Option<PathBuf>,
+    /// write shortlinks stored at an older schema version back at the current one when they are read
+    pub rewrite_old_records: bool,
+    /// without auth, let everyone use the admin pages, backups and imports
+    pub anonymous_admin: bool,
+}
+
+/// A key that must never end up in logs
+#[derive(Clone)]
+pub struct Secret {
+    value: String,
+    generated: bool,
+}
+
+impl Secret {
+    /// `LINKSHRINK_SECRET`, or a random one that only lasts until we restart
+    fn from_env() -> Self {
+        match optional("LINKSHRINK_SECRET") {
+            Some(value) => Self { value,
+                                  generated: false },
+            None => {
+                let mut random = [0u8; 32];
+                OsRng.fill_bytes(&mut random);
+                Self { value: URL_SAFE_NO_PAD.encode(random),
+                       generated: true }
+            }
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.value.as_bytes()
+    }
+
+    /// forms opened before a restart, or served by another instance, won't validate
+    pub fn is_generated(&self) -> bool {
+        self.generated
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Secret")
+         .field("generated", &self.generated)
+         .finish_non_exhaustive()
+    }
+}
+
+/// An OpenID Connect provider to log users in with
+#[derive(Clone)]
+pub struct OidcConfig {
+    /// `/.well-known/openid-configuration` is looked up under this
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    /// where the provider sends users back to, must end in `/auth/callback`
+    pub redirect_url: String,
+    pub scopes: String,
+    /// id token claim used as the user name, falling back to `email` and then `sub`
+    pub user_claim: String,
+}
+
+impl OidcConfig {
+    fn from_env() -> Option<Self> {
+        Some(Self { issuer: optional("LINKSHRINK_OIDC_ISSUER")?.trim_end_matches('/')
+                                                               .to_string(),
+                    client_id: optional("LINKSHRINK_OIDC_CLIENT_ID")?,
+                    client_secret: var("LINKSHRINK_OIDC_CLIENT_SECRET", ""),
+                    redirect_url: optional("LINKSHRINK_OIDC_REDIRECT_URL")?,
+                    scopes: var("LINKSHRINK_OIDC_SCOPES", "openid email profile"),
+                    user_claim: var("LINKSHRINK_OIDC_USER_CLAIM", "preferred_username") })
+    }
+}
+
+impl Config {
+    /// named in `LINKSHRINK_ADMIN_USERS`, rather than an admin through one of their groups
+    pub fn is_admin_user(&self, user: &str) -> bool {
+        self.admin_users
+            .iter()
+            .any(|admin| admin.eq_ignore_ascii_case(user))
+    }
+
+    /// whether we can know who people are, without that every link is open to everyone
+    pub fn auth_enabled(&self) -> bool {
+        self.auth_user_header.is_some() || self.auth_email_header.is_some() || self.oidc.is_some()
+    }
+}
+
+// keep the client secret out of logs
+impl fmt::Debug for OidcConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OidcConfig")
+         .field("issuer", &self.issuer)
+         .field("client_id", &self.client_id)
+         .field("redirect_url", &self.redirect_url)
+         .field("scopes", &self.scopes)
+         .field("user_claim", &self.user_claim)
+         .finish_non_exhaustive()
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Self { redis_host: var("LINKSHRINK_REDIS_HOST", "127.0.0.1"),
+               redis_port: parse("LINKSHRINK_REDIS_PORT", 6379),
+               listen_host: var("LINKSHRINK_LISTEN_HOST", "127.0.0.1"),
+               listen_port: parse("LINKSHRINK_LISTEN_PORT", 8080),
+               allowed_schemes: list("LINKSHRINK_ALLOWED_SCHEMES", "http,https"),
+               public_hosts: list("LINKSHRINK_PUBLIC_HOSTS", ""),
+               reserved_keywords: list("LINKSHRINK_RESERVED_KEYWORDS", ""),
+               forbidden_keyword_patterns: patterns("LINKSHRINK_FORBIDDEN_KEYWORD_PATTERNS"),
+               allowed_domains: list("LINKSHRINK_ALLOWED_DOMAINS", ""),
+               denied_domains: list("LINKSHRINK_DENIED_DOMAINS", ""),
+               auth_user_header: optional("LINKSHRINK_AUTH_USER_HEADER"),
+               auth_email_header: optional("LINKSHRINK_AUTH_EMAIL_HEADER"),
+               auth_groups_header: optional("LINKSHRINK_AUTH_GROUPS_HEADER"),
+               trusted_proxies: networks("LINKSHRINK_TRUSTED_PROXIES"),
+               admin_users: list("LINKSHRINK_ADMIN_USERS", ""),
+               admin_groups: list("LINKSHRINK_ADMIN_GROUPS", ""),
+               oidc: OidcConfig::from_env(),
+               session_ttl: Duration::from_secs(parse("LINKSHRINK_SESSION_TTL_SECONDS",
+                                                      8 * 60 * 60)),
+               private_links_require_auth: parse("LINKSHRINK_PRIVATE_LINKS_REQUIRE_AUTH", false),
+               secret: Secret::from_env(),
+               // off until set, behind a proxy that isn't trusted everyone would share one budget
+               rate_limit_reads: parse("LINKSHRINK_RATE_LIMIT_READS", 0),
+               rate_limit_writes: parse("LINKSHRINK_RATE_LIMIT_WRITES", 0),
+               rate_limit_window:
+                   Duration::from_secs(parse("LINKSHRINK_RATE_LIMIT_WINDOW_SECONDS", 60)),
+               audit_log_file: optional("LINKSHRINK_AUDIT_LOG_FILE").map(PathBuf::from),
+               rewrite_old_records: parse("LINKSHRINK_REWRITE_OLD_RECORDS", false),
+               anonymous_admin: parse("LINKSHRINK_ANONYMOUS_ADMIN", false) }
+    }
+}
+
+fn var(name: &str, default: &str) -> String {
+    std::env::var(name).unwrap_or_else(|_| default.to_string())
+}
+
+fn optional(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+                       .map(|value| value.trim().to_string())
+                       .filter(|value| !value.is_empty())
+}
+
+fn parse<T: FromStr>(name: &str, default: T) -> T {
+    std::env::var(name).ok()
+                       .and_then(|value| value.parse().ok())
+                       .unwrap_or(default)
+}
+
+/// comma separated, lowercased
+fn list(name: &str, default: &str) -> Vec<String> {
+    var(name, default).split(',')
+                      .map(|item| item.trim().to_lowercase())
+                      .filter(|item| !item.is_empty())
+                      .collect()
+}
+
+/// comma separated, case-insensitive regexes
+fn patterns(name: &str) -> Vec<Regex> {
+    var(name, "").split(',')
+                 .map(str::trim)
+                 .filter(|pattern| !pattern.is_empty())
+                 .map(|pattern| {
+                     RegexBuilder::new(pattern).case_insensitive(true)
+                                               .build()
+                                               .unwrap_or_else(|err| {
+                                                   panic!("Invalid pattern in {name}: {err}")
+                                               })
+                 })
+                 .collect()
+}
+
+/// comma separated addresses or CIDR ranges
+fn networks(name: &str) -> Vec<IpNet> {
+    list(name, "").iter()
+                  .map(|network| {
+                      network.parse::<IpNet>()
+                             .or_else(|_| network.parse::<IpAddr>().map(IpNet::from))
+                             .unwrap_or_else(|err| {
+                                 panic!("Invalid network {network:?} in {name}: {err}")
+                             })
+                  })
+                  .collect()
+}
@@ -0,0 +1,263 @@
+use axum::{async_trait,
+           body::Body,
+           extract::{FromRequestParts, State},
+           http::{header::{HeaderMap, HeaderName, CONTENT_TYPE, HOST, ORIGIN, REFERER,
+                           SET_COOKIE},
+                  request::Parts,
+                  Method, Request, StatusCode},
+           middleware::Next,
+           response::{IntoResponse, Response}};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use hmac::{Hmac, Mac};
+use http_body::{LengthLimitError, Limited};
+use serde::Serialize;
+use sha2::Sha256;
+use std::convert::Infallible;
+use tracing::warn;
+use url::{form_urlencoded, Url};
+use uuid::Uuid;
+
+use crate::{api::ApiError, backup::BACKUP_BODY_LIMIT, config::Config, database::AppState,
+            error::error_page, routing::get_cookie, tokens::TokenScope};
+
+const CSRF_COOKIE: &str = "linkshrink_csrf";
+/// forms send the token in this field
+const CSRF_FIELD: &str = "csrf_token";
+/// scripts using a browser session send it in this header instead
+const CSRF_HEADER: &str = "X-CSRF-Token";
+/// what axum allows a form by default, the body is read before any route's limit applies
+const FORM_BODY_LIMIT: usize = 2 * 1024 * 1024;
+const BODY_TOO_LARGE: &str = "body too large";
+
+/// The token every form rendered for this request has to send back, attached by [`protect`]
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CsrfToken(String);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for CsrfToken where S: Send + Sync
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts.extensions
+                .get::<CsrfToken>()
+                .cloned()
+                .unwrap_or_default())
+    }
+}
+
+fn mac(config: &Config, nonce: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(config.secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(nonce.as_bytes());
+    mac
+}
+
+/// Double submit: the browser holds a random nonce in a cookie another site can't read, and our
+/// forms carry its signature, which another site can't forge
+pub async fn protect(State(state): State<AppState>,
+                     request: Request<Body>,
+                     next: Next<Body>)
+                     -> Response {
+    let config = state.get_config();
+    let existing = get_cookie(request.headers(), CSRF_COOKIE).filter(|nonce| !nonce.is_empty())
+                                                             .map(str::to_string);
+    let fresh = existing.is_none();
+    let nonce = existing.unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    // API tokens aren't sent along by browsers on their own, so they can't be abused this way
+    let checked = !matches!(*request.method(),
+                            Method::GET | Method::HEAD | Method::OPTIONS)
+                  && request.extensions().get::<TokenScope>().is_none();
+
+    let api = request.uri().path().starts_with("/api/");
+    let mut request = match checked {
+        true => match check(config, &nonce, request).await {
+            Ok(request) => request,
+            Err(BODY_TOO_LARGE) if api => {
+                return ApiError::new(StatusCode::PAYLOAD_TOO_LARGE,
+                                     "too_large",
+                                     "The request body is too large").into_response();
+            }
+            Err(BODY_TOO_LARGE) => {
+                return error_page(state.get_engine(),
+                                  StatusCode::PAYLOAD_TOO_LARGE,
+                                  "This form is too large to send.".to_string());
+            }
+            Err(reason) if api => {
+                warn!(reason, "Rejected an API request failing the CSRF check");
+                return ApiError::new(StatusCode::FORBIDDEN,
+                                     "cross_site",
+                                     "Send JSON, an API token or the X-CSRF-Token header, from this site").into_response();
+            }
+            Err(reason) => {
+                warn!(reason, "Rejected a request failing the CSRF check");
+                return error_page(state.get_engine(),
+                                  StatusCode::FORBIDDEN,
+                                  "This form has expired or was sent from another site. Go back, reload the page and try again.".to_string());
+            }
+        },
+        false => request,
+    };
+
+    let token = URL_SAFE_NO_PAD.encode(mac(config, &nonce).finalize().into_bytes());
+    request.extensions_mut().insert(CsrfToken(token));
+
+    let mut response = next.run(request).await;
+    if fresh {
+        let cookie = format!("{CSRF_COOKIE}={nonce}; Path=/; HttpOnly; SameSite=Lax");
+        if let Ok(cookie) = cookie.parse() {
+            response.headers_mut().append(SET_COOKIE, cookie);
+        }
+    }
+
+    response
+}
+
+/// the request back, if it came from one of our own pages
+async fn check(config: &Config,
+               nonce: &str,
+               request: Request<Body>)
+               -> Result<Request<Body>, &'static str> {
+    if !same_origin(config, request.headers()) {
+        return Err("cross-origin request");
+    }
+
+    if let Some(token) = request.headers()
+                                .get(CSRF_HEADER)
+                                .and_then(|token| token.to_str().ok())
+    {
+        return match valid(config, nonce, token) {
+            true => Ok(request),
+            false => Err("invalid token header"),
+        };
+    }
+
+    let content_type = request.headers()
+                              .get(CONTENT_TYPE)
+                              .and_then(|content_type| content_type.to_str().ok())
+                              .unwrap_or_default()
+                              .to_ascii_lowercase();
+
+    // another site can only send JSON, or methods other than POST, with fetch, which asks us first
+    // and is refused, so scripts can use the API without a token
+    if *request.method() != Method::POST || content_type.starts_with("application/json") {
+        return Ok(request);
+    }
+
+    if !content_type.starts_with("application/x-www-form-urlencoded") {
+        return Err("no token");
+    }
+
+    // the handler still needs the body, so read it and put it back, backups being the only
+    // forms allowed to be big
+    let (parts, body) = request.into_parts();
+    let limit = match parts.uri.path().starts_with("/admin/backup") {
+        true => BACKUP_BODY_LIMIT,
+        false => FORM_BODY_LIMIT,
+    };
+    let body = match hyper::body::to_bytes(Limited::new(body, limit)).await {
+        Ok(body) => body,
+        Err(err) if err.is::<LengthLimitError>() => return Err(BODY_TOO_LARGE),
+        Err(_) => return Err("unreadable body"),
+    };
+
+    let token = form_urlencoded::parse(&body).find(|(name, _)| name == CSRF_FIELD)
+                                             .map(|(_, token)| token.into_owned())
+                                             .ok_or("no token")?;
+    if !valid(config, nonce, &token) {
+        return Err("invalid token");
+    }
+
+    Ok(Request::from_parts(parts, Body::from(body)))
+}
+
+fn valid(config: &Config, nonce: &str, token: &str) -> bool {
+    URL_SAFE_NO_PAD.decode(token)
+                   .is_ok_and(|token| mac(config, nonce).verify_slice(&token).is_ok())
+}
+
+/// `Origin`, or failing that `Referer`, has to be us, when the browser sends either
+fn same_origin(config: &Config, headers: &HeaderMap) -> bool {
+    let header = |name: HeaderName| headers.get(name).and_then(|value| value.to_str().ok());
+
+    let Some(source) = header(ORIGIN).or_else(|| header(REFERER)) else {
+        return true;
+    };
+    let Some(source) = Url::parse(source).ok() else {
+        return false;
+    };
+    let Some(host) = source.host_str() else {
+        return false;
+    };
+
+    let authority = match source.port() {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_string(),
+    };
+
+    header(HOST).is_some_and(|ours| ours.eq_ignore_ascii_case(&authority))
+    || config.public_hosts
+             .iter()
+             .any(|public| public.eq_ignore_ascii_case(host))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn config() -> Config {
+        Config { public_hosts: vec!["go.example.com".to_string()],
+                 ..Config::from_env() }
+    }
+
+    fn headers(pairs: &[(HeaderName, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(name.clone(), HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn requests_from_our_own_pages_are_same_origin() {
+        let config = config();
+
+        assert!(same_origin(&config, &headers(&[(HOST, "localhost:8080")])));
+        assert!(same_origin(&config,
+                            &headers(&[(HOST, "localhost:8080"),
+                                       (ORIGIN, "http://localhost:8080")])));
+        assert!(same_origin(&config,
+                            &headers(&[(HOST, "go"),
+                                       (REFERER, "https://go.example.com/edit/docs")])));
+    }
+
+    #[test]
+    fn requests_from_other_sites_are_not() {
+        let config = config();
+
+        for (origin, host) in [("https://evil.com", "localhost:8080"),
+                               ("http://localhost:3000", "localhost:8080"),
+                               ("http://localhost", "localhost:8080"),
+                               ("null", "localhost:8080"),
+                               ("https://go.example.com.evil.com", "go")]
+        {
+            assert!(!same_origin(&config, &headers(&[(HOST, host), (ORIGIN, origin)])),
+                    "{origin} to {host}");
+        }
+        assert!(!same_origin(&config,
+                             &headers(&[(HOST, "localhost:8080"),
+                                        (REFERER, "https://evil.com/page")])));
+    }
+
+    #[test]
+    fn tokens_are_tied_to_their_nonce() {
+        let config = config();
+        let token = URL_SAFE_NO_PAD.encode(mac(&config, "nonce").finalize().into_bytes());
+
+        assert!(valid(&config, "nonce", &token));
+        assert!(!valid(&config, "other", &token));
+        assert!(!valid(&config, "nonce", "not base64!"));
+        assert!(!valid(&config, "nonce", ""));
+    }
+}
@@ -2,17 +2,17 @@ use axum::{extract::FromRef,
            http::StatusCode,
            response::{IntoResponse, Response}};
 use axum_template::engine::Engine;
+use chrono::Utc;
 use handlebars::Handlebars;
 use lockfree::map::Map as LFMap;
-use std::{sync::Arc, time::Duration};
-
-use crate::models::Shortlink;
-use redis_async::{client,
-                  client::PairedConnection,
-                  error::Error as RedisError,
-                  resp::{FromResp, RespValue},
-                  resp_array};
-use serde::Serialize;
+use std::{cmp::Reverse, sync::Arc, time::Duration};
+
+use crate::{config::Config,
+            models::{Shortlink, TrashedShortlink},
+            oidc::Oidc,
+            schema};
+use redis_async::{client, client::PairedConnection, resp::RespValue, resp_array};
+use serde::{de::DeserializeOwned, Serialize};
 use tap::TapFallible;
 use thiserror::Error;
 use tracing::{debug, error, instrument, trace};
@@ -21,12 +21,16 @@ pub type TemplateEngine = Engine<Handlebars<'static>>;
 pub type RedisConnection = Arc<PairedConnection>;
 
 const FLUSH_SLEEP_DURATION: Duration = Duration::from_secs(5);
+/// how many keys bulk jobs like imports, restores and migrations read or write in one round trip
+pub const BATCH_SIZE: usize = 100;
 
 #[derive(Clone, FromRef)]
 pub struct AppState {
     cache: Arc<LFMap<String, Shortlink>>,
     engine: TemplateEngine,
     connection: RedisConnection,
+    config: Arc<Config>,
+    oidc: Option<Arc<Oidc>>,
 }
 
 impl AppState {
@@ -34,10 +38,18 @@ impl AppState {
         self.engine.clone()
     }
 
-    #[instrument]
-    pub async fn new(host: &str, port: u16) -> Result<Self, DatabaseError> {
+    pub fn get_config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn get_oidc(&self) -> Option<&Oidc> {
+        self.oidc.as_deref()
+    }
+
+    #[instrument(skip(config))]
+    pub async fn new(config: Config) -> Result<Self, DatabaseError> {
         let connection =
-            client::paired_connect(host, port).await
+            client::paired_connect(&config.redis_host, config.redis_port).await
                                               .tap_err(|err| {
                                                   error!("Failed to connect to redis: {err:#?}")
                                               })
@@ -51,7 +63,9 @@ impl AppState {
 
         let state = Self { cache: Arc::new(LFMap::default()),
                            engine: Engine::from(handlebars),
-                           connection: Arc::new(connection) };
+                           connection: Arc::new(connection),
+                           oidc: config.oidc.clone().map(|oidc| Arc::new(Oidc::new(oidc))),
+                           config: Arc::new(config) };
 
         let weak_cache = Arc::downgrade(&state.cache);
         let weak_connection = Arc::downgrade(&state.connection);
@@ -108,18 +122,37 @@ impl AppState {
         let cache_fetch = keys.iter().map(|key| self.cache.get(&key[4..]));
 
         // todo: fetch from cache and determine _which_ keys we actually need to fetch from redis
-        let shortlink_results =
-            self.connection
-                .send::<Vec<Shortlink>>(RespValue::Array(mget_query))
-                .await
-                .map_err(|_| DatabaseError::FailedToQueryRedis)?
-                .into_iter()
-                .zip(cache_fetch)
-                .map(|(redis_shortlink, maybe_cache_shortlink)| match maybe_cache_shortlink {
-                         Some(cached_shortlink) => cached_shortlink.val().clone(),
-                         _ => redis_shortlink,
-                     })
-                .collect::<Vec<Shortlink>>();
+        let values = self.connection
+                         .send::<Vec<Option<String>>>(RespValue::Array(mget_query))
+                         .await
+                         .map_err(|_| DatabaseError::FailedToQueryRedis)?;
+
+        let mut shortlink_results = Vec::with_capacity(keys.len());
+        let mut upgraded = vec![];
+        for ((key, value), maybe_cache_shortlink) in keys.iter().zip(values).zip(cache_fetch) {
+            if let Some(cached_shortlink) = maybe_cache_shortlink {
+                shortlink_results.push(cached_shortlink.val().clone());
+                continue;
+            }
+
+            // deleted since the KEYS
+            let Some(value) = value else {
+                continue;
+            };
+            // one broken record shouldn't take the whole listing down with it
+            let Ok(decoded) = schema::decode(&value).tap_err(|err| {
+                                                        error!(key, %err, "Failed to decode shortlink")
+                                                    })
+            else {
+                continue;
+            };
+            if decoded.upgraded && self.config.rewrite_old_records {
+                upgraded.push(decoded.shortlink.clone());
+            }
+            shortlink_results.push(decoded.shortlink);
+        }
+
+        self.put_shortlinks(&upgraded).await?;
 
         Ok(shortlink_results)
     }
@@ -133,28 +166,155 @@ impl AppState {
         }
 
         let entry = self.connection
-                        .send::<Option<Shortlink>>(resp_array!["GET", format!("sl::{keyword}")])
+                        .send::<Option<String>>(resp_array!["GET", format!("sl::{keyword}")])
                         .await
-                        .map_err(|_| DatabaseError::FailedToQueryRedis)?;
-
-        if let Some(shortlink) = &entry {
+                        .map_err(|_| DatabaseError::FailedToQueryRedis)?
+                        .map(|value| schema::decode(&value))
+                        .transpose()
+                        .tap_err(|err| error!(keyword, %err, "Failed to decode shortlink"))
+                        .map_err(|_| DatabaseError::FailedToDecode)?;
+
+        let Some(decoded) = entry else {
+            return Ok(None);
+        };
+
+        // the flush writes every cached link back at the current version, so an outdated one is
+        // only cached when it may be rewritten, or once it changes, e.g. by counting a hit
+        if !decoded.upgraded || self.config.rewrite_old_records {
             debug!("Entry set into cache");
-            self.cache.insert(keyword.to_string(), shortlink.clone());
+            self.cache
+                .insert(keyword.to_string(), decoded.shortlink.clone());
         }
 
-        Ok(entry)
+        Ok(Some(decoded.shortlink))
     }
 
-    pub async fn hit_shortlink(&self, keyword: &str) -> Result<(), DatabaseError> {
-        let mut shortlink: Shortlink = self.get_shortlink(keyword)
-                                           .await?
-                                           .expect("Cannot find shortlink");
+    /// count a hit on a shortlink, and on the weighted destination it was sent to if any
+    pub async fn hit_shortlink(&self,
+                               keyword: &str,
+                               destination: Option<usize>)
+                               -> Result<(), DatabaseError> {
+        // deleted between following it and counting the hit
+        let Some(mut shortlink) = self.get_shortlink(keyword).await? else {
+            return Ok(());
+        };
         shortlink.hits += 1;
+        if let Some(destination) =
+            destination.and_then(|index| shortlink.destinations.get_mut(index))
+        {
+            destination.hits += 1;
+        }
         // only update the cache with the hit
         self.cache.insert(keyword.to_string(), shortlink);
         Ok(())
     }
 
+    /// count a use of a limited shortlink, returning how often it has been used including this one
+    ///
+    /// hits are only counted in the cache, this goes straight to redis so that two visitors can
+    /// never both get the last use
+    pub async fn claim_use(&self, keyword: &str) -> Result<u64, DatabaseError> {
+        self.connection
+            .send::<i64>(resp_array!["INCR", uses_key(keyword)])
+            .await
+            .map(|uses| uses as u64)
+            .map_err(|_| DatabaseError::FailedToQueryRedis)
+    }
+
+    /// how often a limited shortlink has been used
+    pub async fn get_uses(&self, keyword: &str) -> Result<u64, DatabaseError> {
+        Ok(self.connection
+               .send::<Option<String>>(resp_array!["GET", uses_key(keyword)])
+               .await
+               .map_err(|_| DatabaseError::FailedToQueryRedis)?
+               .and_then(|uses| uses.parse().ok())
+               .unwrap_or_default())
+    }
+
+    /// for restoring backups
+    pub async fn set_uses(&self, keyword: &str, uses: u64) -> Result<(), DatabaseError> {
+        self.connection
+            .send::<()>(resp_array!["SET", uses_key(keyword), uses.to_string()])
+            .await
+            .map_err(|_| DatabaseError::FailedToQueryRedis)
+    }
+
+    pub async fn reset_uses(&self, keyword: &str) -> Result<(), DatabaseError> {
+        self.delete_key(&uses_key(keyword)).await
+    }
+
+    /// save a created or changed shortlink, `previous` being what it was before if anything
+    pub async fn save_shortlink(&self,
+                                previous: Option<&Shortlink>,
+                                mut shortlink: Shortlink)
+                                -> Result<Shortlink, DatabaseError> {
+        shortlink.modified = Some(Utc::now());
+        self.store_shortlink(shortlink.clone()).await?;
+
+        // a new link, or a new limit, starts counting from zero
+        if previous.is_none_or(|previous| previous.max_uses != shortlink.max_uses) {
+            self.reset_uses(&shortlink.keyword).await?;
+        }
+
+        Ok(shortlink)
+    }
+
+    /// save a batch of created or changed shortlinks, like [`AppState::save_shortlink`] each comes
+    /// with what it was before if anything
+    ///
+    /// hits are saved as given rather than kept from the cache
+    pub async fn save_shortlinks(&self,
+                                 changes: Vec<(Option<Shortlink>, Shortlink)>)
+                                 -> Result<Vec<Shortlink>, DatabaseError> {
+        let now = Utc::now();
+        let mut reset: Vec<RespValue> = vec!["DEL".into()];
+        let mut saved = Vec::with_capacity(changes.len());
+        for (previous, mut shortlink) in changes {
+            shortlink.modified = Some(now);
+            if previous.is_none_or(|previous| previous.max_uses != shortlink.max_uses) {
+                reset.push(uses_key(&shortlink.keyword).into());
+            }
+            saved.push(shortlink);
+        }
+
+        self.put_shortlinks(&saved).await?;
+        if reset.len() > 1 {
+            self.connection
+                .send::<i64>(RespValue::Array(reset))
+                .await
+                .map_err(|_| DatabaseError::FailedToQueryRedis)?;
+        }
+
+        Ok(saved)
+    }
+
+    /// write shortlinks exactly as given with a single MSET, for imports and restoring backups
+    pub async fn put_shortlinks(&self, shortlinks: &[Shortlink]) -> Result<(), DatabaseError> {
+        if shortlinks.is_empty() {
+            return Ok(());
+        }
+
+        let mut mset: Vec<RespValue> = vec!["MSET".into()];
+        for shortlink in shortlinks {
+            let serialized =
+                schema::encode(shortlink).map_err(|_| DatabaseError::FailedToQueryRedis)?;
+            mset.push(format!("sl::{}", shortlink.keyword).into());
+            mset.push(serialized.into());
+        }
+
+        self.connection
+            .send::<()>(RespValue::Array(mset))
+            .await
+            .map_err(|_| DatabaseError::FailedToQueryRedis)?;
+
+        for shortlink in shortlinks {
+            self.cache
+                .insert(shortlink.keyword.clone(), shortlink.clone());
+        }
+
+        Ok(())
+    }
+
     /// save a shortlink
     pub async fn store_shortlink(&self, mut shortlink: Shortlink) -> Result<(), DatabaseError> {
         if let Some(existing_cache) = self.cache.get(&shortlink.keyword) {
@@ -163,7 +323,7 @@ impl AppState {
         }
 
         self.connection
-            .send(shortlink.set_key_in_redis()?)
+            .send::<()>(shortlink.set_key_in_redis()?)
             .await
             .map_err(|_| DatabaseError::FailedToQueryRedis)?;
 
@@ -172,6 +332,266 @@ impl AppState {
 
         Ok(())
     }
+
+    /// move a shortlink into the trash, where admins can restore or purge it
+    #[instrument(skip(self, shortlink), fields(keyword = shortlink.keyword))]
+    pub async fn trash_shortlink(&self,
+                                 shortlink: Shortlink,
+                                 deleted_by: Option<String>)
+                                 -> Result<(), DatabaseError> {
+        let keyword = shortlink.keyword.clone();
+        self.store_trashed(&TrashedShortlink { shortlink,
+                                               deleted: Utc::now(),
+                                               deleted_by })
+            .await?;
+
+        // drop it from the cache first so the background flush can't write it back
+        self.cache.remove(&keyword);
+        self.delete_key(&format!("sl::{keyword}")).await
+    }
+
+    /// put a link into the trash as is, for restoring backups
+    pub async fn store_trashed(&self, trashed: &TrashedShortlink) -> Result<(), DatabaseError> {
+        self.set_json(&format!("trash::{}", trashed.shortlink.keyword), trashed)
+            .await
+    }
+
+    /// everything in the trash, most recently deleted first
+    pub async fn get_trash(&self) -> Result<Vec<TrashedShortlink>, DatabaseError> {
+        let keys: Vec<String> = self.connection
+                                    .send(resp_array!["KEYS", "trash::*"])
+                                    .await
+                                    .map_err(|_| DatabaseError::FailedToQueryRedis)?;
+
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut mget_query = vec!["MGET".into()];
+        mget_query.extend(keys.iter().map(|key| key.into()));
+
+        let mut trash = self.connection
+                            .send::<Vec<Option<String>>>(RespValue::Array(mget_query))
+                            .await
+                            .map_err(|_| DatabaseError::FailedToQueryRedis)?
+                            .into_iter()
+                            .flatten()
+                            .filter_map(|serialized| {
+                                serde_json::from_str::<TrashedShortlink>(&serialized)
+                                    .tap_err(|err| error!("Failed to deserialize trashed shortlink: {err}"))
+                                    .ok()
+                            })
+                            .collect::<Vec<_>>();
+
+        trash.sort_by_key(|trashed| Reverse(trashed.deleted));
+
+        Ok(trash)
+    }
+
+    /// put a trashed shortlink back, unless its keyword has been taken again since
+    pub async fn restore_shortlink(&self, keyword: &str) -> Result<RestoreOutcome, DatabaseError> {
+        let trash_key = format!("trash::{keyword}");
+        let Some(trashed) = self.get_json::<TrashedShortlink>(&trash_key).await? else {
+            return Ok(RestoreOutcome::NotInTrash);
+        };
+
+        if self.get_shortlink(keyword).await?.is_some() {
+            return Ok(RestoreOutcome::KeywordTaken);
+        }
+
+        self.store_shortlink(trashed.shortlink).await?;
+        self.delete_key(&trash_key).await?;
+
+        Ok(RestoreOutcome::Restored)
+    }
+
+    /// delete trashed shortlinks for good
+    pub async fn purge_trash(&self, keywords: &[String]) -> Result<(), DatabaseError> {
+        for keyword in keywords {
+            self.delete_key(&format!("trash::{keyword}")).await?;
+            self.reset_uses(keyword).await?;
+        }
+
+        Ok(())
+    }
+
+    /// store a value as json under `key`
+    pub async fn set_json<T: Serialize>(&self, key: &str, value: &T) -> Result<(), DatabaseError> {
+        let serialized =
+            serde_json::to_string(value).map_err(|_| DatabaseError::FailedToQueryRedis)?;
+
+        self.connection
+            .send::<()>(resp_array!["SET", key, serialized])
+            .await
+            .map_err(|_| DatabaseError::FailedToQueryRedis)
+    }
+
+    /// store a value as json under `key`, redis forgets it after `ttl`
+    pub async fn set_expiring<T: Serialize>(&self,
+                                            key: &str,
+                                            value: &T,
+                                            ttl: Duration)
+                                            -> Result<(), DatabaseError> {
+        let serialized =
+            serde_json::to_string(value).map_err(|_| DatabaseError::FailedToQueryRedis)?;
+
+        self.connection
+            .send::<()>(resp_array!["SET", key, serialized, "EX", ttl.as_secs().to_string()])
+            .await
+            .map_err(|_| DatabaseError::FailedToQueryRedis)
+    }
+
+    /// fetch a json value stored with [`AppState::set_json`] or [`AppState::set_expiring`]
+    pub async fn get_json<T: DeserializeOwned>(&self,
+                                               key: &str)
+                                               -> Result<Option<T>, DatabaseError> {
+        let serialized = self.connection
+                             .send::<Option<String>>(resp_array!["GET", key])
+                             .await
+                             .map_err(|_| DatabaseError::FailedToQueryRedis)?;
+
+        serialized.map(|serialized| serde_json::from_str(&serialized))
+                  .transpose()
+                  .map_err(|_| DatabaseError::FailedToQueryRedis)
+    }
+
+    /// count a request in a fixed window, returning how many have been made in it so far
+    pub async fn count_request(&self, key: &str, window: Duration) -> Result<u64, DatabaseError> {
+        let count = self.connection
+                        .send::<i64>(resp_array!["INCR", key])
+                        .await
+                        .map_err(|_| DatabaseError::FailedToQueryRedis)?;
+
+        // the first request of a window starts its clock
+        if count == 1 {
+            self.connection
+                .send::<i64>(resp_array!["EXPIRE", key, window.as_secs().to_string()])
+                .await
+                .map_err(|_| DatabaseError::FailedToQueryRedis)?;
+        }
+
+        Ok(count as u64)
+    }
+
+    pub async fn push_to_list(&self, key: &str, value: &str) -> Result<(), DatabaseError> {
+        self.connection
+            .send::<i64>(resp_array!["LPUSH", key, value])
+            .await
+            .map_err(|_| DatabaseError::FailedToQueryRedis)?;
+
+        Ok(())
+    }
+
+    /// add values to the end of a list, in order
+    pub async fn append_to_list(&self, key: &str, values: &[String]) -> Result<(), DatabaseError> {
+        if values.is_empty() {
+            return Ok(());
+        }
+
+        let mut rpush: Vec<RespValue> = vec!["RPUSH".into(), key.into()];
+        rpush.extend(values.iter().map(|value| value.as_str().into()));
+        self.connection
+            .send::<i64>(RespValue::Array(rpush))
+            .await
+            .map_err(|_| DatabaseError::FailedToQueryRedis)?;
+
+        Ok(())
+    }
+
+    /// `start` and `stop` are inclusive, as in redis
+    pub async fn list_range(&self,
+                            key: &str,
+                            start: isize,
+                            stop: isize)
+                            -> Result<Vec<String>, DatabaseError> {
+        self.connection
+            .send(resp_array!["LRANGE", key, start.to_string(), stop.to_string()])
+            .await
+            .map_err(|_| DatabaseError::FailedToQueryRedis)
+    }
+
+    pub async fn add_to_set(&self, key: &str, member: &str) -> Result<(), DatabaseError> {
+        self.connection
+            .send::<i64>(resp_array!["SADD", key, member])
+            .await
+            .map_err(|_| DatabaseError::FailedToQueryRedis)?;
+
+        Ok(())
+    }
+
+    pub async fn remove_from_set(&self, key: &str, member: &str) -> Result<(), DatabaseError> {
+        self.connection
+            .send::<i64>(resp_array!["SREM", key, member])
+            .await
+            .map_err(|_| DatabaseError::FailedToQueryRedis)?;
+
+        Ok(())
+    }
+
+    pub async fn set_members(&self, key: &str) -> Result<Vec<String>, DatabaseError> {
+        self.connection
+            .send(resp_array!["SMEMBERS", key])
+            .await
+            .map_err(|_| DatabaseError::FailedToQueryRedis)
+    }
+
+    /// every key matching `pattern`, a page at a time so redis isn't blocked the way KEYS does
+    pub async fn scan_keys(&self, pattern: &str) -> Result<Vec<String>, DatabaseError> {
+        let mut keys = vec![];
+        let mut cursor = "0".to_string();
+        loop {
+            let (next, page) =
+                self.connection
+                    .send::<(String, Vec<String>)>(resp_array!["SCAN", cursor, "MATCH", pattern,
+                                                               "COUNT", "1000"])
+                    .await
+                    .map_err(|_| DatabaseError::FailedToQueryRedis)?;
+            keys.extend(page);
+            if next == "0" {
+                break;
+            }
+            cursor = next;
+        }
+
+        // a key can come up twice while redis rehashes
+        keys.sort();
+        keys.dedup();
+        Ok(keys)
+    }
+
+    /// the raw values of `keys`, `None` for missing keys and ones that aren't strings
+    pub async fn get_strings(&self, keys: &[String]) -> Result<Vec<Option<String>>, DatabaseError> {
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut mget: Vec<RespValue> = vec!["MGET".into()];
+        mget.extend(keys.iter().map(|key| key.as_str().into()));
+        self.connection
+            .send(RespValue::Array(mget))
+            .await
+            .map_err(|_| DatabaseError::FailedToQueryRedis)
+    }
+
+    pub async fn delete_key(&self, key: &str) -> Result<(), DatabaseError> {
+        self.connection
+            .send::<i64>(resp_array!["DEL", key])
+            .await
+            .map_err(|_| DatabaseError::FailedToQueryRedis)?;
+
+        Ok(())
+    }
+}
+
+fn uses_key(keyword: &str) -> String {
+    format!("uses::{keyword}")
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RestoreOutcome {
+    Restored,
+    NotInTrash,
+    KeywordTaken,
 }
 
 #[derive(Debug, Serialize, Error)]
@@ -182,6 +602,8 @@ pub enum DatabaseError {
     FailedToQueryRedis,
     #[error("Failed to evict cache")]
     FailedToEvictCache,
+    #[error("Failed to decode a stored record")]
+    FailedToDecode,
 }
 
 impl IntoResponse for DatabaseError {
@@ -190,20 +612,10 @@ impl IntoResponse for DatabaseError {
     }
 }
 
-impl FromResp for Shortlink {
-    fn from_resp_int(resp: RespValue) -> Result<Self, RedisError> {
-        let serialized = String::from_resp(resp)?;
-
-        serde_json::from_str::<Shortlink>(&serialized)
-            .map_err(|_| RedisError::Unexpected("Failed to deserialize data".to_string()))
-    }
-}
-
 impl Shortlink {
     fn set_key_in_redis(&self) -> Result<RespValue, DatabaseError> {
         let keyword = self.keyword.clone();
-        let serialized =
-            serde_json::to_string(self).map_err(|_| DatabaseError::FailedToQueryRedis)?;
+        let serialized = schema::encode(self).map_err(|_| DatabaseError::FailedToQueryRedis)?;
 
         Ok(resp_array!["SET", format!("sl::{keyword}"), serialized])
     }
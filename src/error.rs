@@ -1,5 +1,9 @@
 use axum::{http::StatusCode,
            response::{IntoResponse, Response}};
+use axum_template::RenderHtml;
+use serde_json::json;
+
+use crate::database::TemplateEngine;
 
 pub type AppResult<T> = Result<T, ErrorWrapper>;
 
@@ -21,3 +25,19 @@ impl IntoResponse for ErrorWrapper {
         ).into_response()
     }
 }
+
+/// render a friendly error page, for problems the user can do something about
+pub fn error_page(engine: TemplateEngine,
+                  status: StatusCode,
+                  message: impl Into<String>)
+                  -> Response {
+    (status,
+     RenderHtml("error",
+                engine,
+                json!({
+                    "status": status.as_u16(),
+                    "reason": status.canonical_reason(),
+                    "message": message.into()
+                })))
+                    .into_response()
+}
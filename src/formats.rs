@@ -0,0 +1,215 @@
+use axum::{async_trait,
+           extract::FromRequestParts,
+           http::{header::{HeaderValue, ACCEPT, CONTENT_TYPE, VARY},
+                  request::Parts},
+           response::{IntoResponse, Response}};
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+
+use crate::{error::AppResult,
+            models::{Destination, RedirectKind, Shortlink},
+            rules::Rule};
+
+/// How a page can be sent, picked from the request's `Accept` header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Html,
+    Json,
+    Csv,
+}
+
+impl Format {
+    /// the type the client likes best, browsers and anything asking for `*/*` get html
+    fn preferred(accept: &str) -> Self {
+        let mut best = (Format::Html, 0.0);
+        for range in accept.split(',') {
+            let mut params = range.split(';');
+            let format = match params.next().unwrap_or_default().trim() {
+                "text/html" | "application/xhtml+xml" | "text/*" | "*/*" => Format::Html,
+                "application/json" => Format::Json,
+                "text/csv" => Format::Csv,
+                _ => continue,
+            };
+            let quality = params.filter_map(|param| param.trim().strip_prefix("q="))
+                                .find_map(|quality| quality.parse::<f32>().ok())
+                                .unwrap_or(1.0);
+
+            // the first of equally good types wins
+            if quality > best.1 {
+                best = (format, quality);
+            }
+        }
+
+        best.0
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Format where S: Send + Sync
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts.headers
+                .get(ACCEPT)
+                .and_then(|accept| accept.to_str().ok())
+                .map_or(Format::Html, Format::preferred))
+    }
+}
+
+/// the response depends on `Accept`, caches have to know
+pub fn vary(mut response: Response) -> Response {
+    response.headers_mut()
+            .append(VARY, HeaderValue::from_static("accept"));
+    response
+}
+
+/// One shortlink as a CSV row, lists are written the way the edit page takes them
+///
+/// When reading, every column but `keyword` and `url` may be left out
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CsvShortlink {
+    keyword: String,
+    url: String,
+    owner: String,
+    description: String,
+    private: bool,
+    redirect_kind: RedirectKind,
+    hits: usize,
+    modified: Option<DateTime<Utc>>,
+    sticky: bool,
+    max_uses: Option<u64>,
+    /// only whether there is a password, never its hash
+    protected: bool,
+    /// comma separated
+    co_owners: String,
+    /// one `weight url` per line
+    destinations: String,
+    /// one `condition => url` per line
+    rules: String,
+}
+
+impl From<&Shortlink> for CsvShortlink {
+    fn from(shortlink: &Shortlink) -> Self {
+        Self { keyword: shortlink.keyword.clone(),
+               url: shortlink.url.clone(),
+               owner: shortlink.owner.clone(),
+               description: shortlink.description.clone(),
+               private: shortlink.private,
+               redirect_kind: shortlink.redirect_kind,
+               hits: shortlink.hits,
+               modified: shortlink.modified,
+               sticky: shortlink.sticky,
+               max_uses: shortlink.max_uses,
+               protected: shortlink.is_protected(),
+               co_owners: co_owners_text(&shortlink.co_owners),
+               destinations: destinations_text(&shortlink.destinations),
+               rules: rules_text(&shortlink.rules) }
+    }
+}
+
+/// a link back from a row, failing on the first list entry that doesn't parse
+impl TryFrom<CsvShortlink> for Shortlink {
+    type Error = String;
+
+    fn try_from(row: CsvShortlink) -> Result<Self, Self::Error> {
+        let destinations = parse_destinations(&row.destinations, &[])?;
+        let rules = parse_rules(&row.rules)?;
+        let co_owners = parse_co_owners(&row.co_owners);
+
+        Ok(Shortlink { keyword: row.keyword,
+                       url: row.url,
+                       owner: row.owner,
+                       hits: row.hits,
+                       private: row.private,
+                       description: row.description,
+                       modified: row.modified,
+                       redirect_kind: row.redirect_kind,
+                       destinations,
+                       sticky: row.sticky,
+                       rules,
+                       co_owners,
+                       password_hash: None,
+                       max_uses: row.max_uses })
+    }
+}
+
+/// co-owners are edited as a comma separated list
+pub fn parse_co_owners(input: &str) -> Vec<String> {
+    input.split(',')
+         .map(str::trim)
+         .filter(|co_owner| !co_owner.is_empty())
+         .map(str::to_string)
+         .collect()
+}
+
+pub fn co_owners_text(co_owners: &[String]) -> String {
+    co_owners.join(", ")
+}
+
+/// destinations are edited as text, one per line, keeping the hit counts of destinations that are
+/// still present
+pub fn parse_destinations(input: &str,
+                          previous: &[Destination])
+                          -> Result<Vec<Destination>, String> {
+    input.lines()
+         .filter(|line| !line.trim().is_empty())
+         .map(|line| {
+             let mut destination = line.parse::<Destination>()?;
+             destination.hits = previous.iter()
+                                        .find(|d| d.url == destination.url)
+                                        .map_or(0, |d| d.hits);
+             Ok(destination)
+         })
+         .collect()
+}
+
+pub fn destinations_text(destinations: &[Destination]) -> String {
+    destinations.iter()
+                .map(Destination::to_string)
+                .collect::<Vec<_>>()
+                .join("\n")
+}
+
+/// rules are edited as text, one per line
+pub fn parse_rules(input: &str) -> Result<Vec<Rule>, String> {
+    input.lines()
+         .filter(|line| !line.trim().is_empty())
+         .map(str::parse::<Rule>)
+         .collect()
+}
+
+pub fn rules_text(rules: &[Rule]) -> String {
+    rules.iter()
+         .map(Rule::to_string)
+         .collect::<Vec<_>>()
+         .join("\n")
+}
+
+/// a time the way other tools write them, RFC 3339, an offset without a colon like Bitly's, or a
+/// bare UTC time like YOURLS's
+pub fn parse_timestamp(text: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(text).or_else(|_| {
+                                          DateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%S%z")
+                                      })
+                                      .map(|time| time.with_timezone(&Utc))
+                                      .ok()
+                                      .or_else(|| {
+                                          NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S")
+                                              .ok()
+                                              .map(|time| Utc.from_utc_datetime(&time))
+                                      })
+}
+
+/// links as a CSV file with a header row
+pub fn csv<'a>(shortlinks: impl IntoIterator<Item = &'a Shortlink>) -> AppResult<Response> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    for shortlink in shortlinks {
+        writer.serialize(CsvShortlink::from(shortlink))?;
+    }
+    let body = writer.into_inner().map_err(|err| err.into_error())?;
+
+    Ok(([(CONTENT_TYPE, "text/csv; charset=utf-8")], body).into_response())
+}
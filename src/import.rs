@@ -0,0 +1,430 @@
+use axum::{extract::{Query, State},
+           http::header::{HeaderMap, CONTENT_TYPE, HOST},
+           response::{IntoResponse, Response},
+           Form, Json};
+use axum_template::RenderHtml;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use tracing::{info, instrument};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{api::{ApiError, ApiResult},
+            audit::{self, Actor, AuditAction},
+            csrf::CsrfToken,
+            database::{AppState, DatabaseError, BATCH_SIZE},
+            error::AppResult,
+            formats::CsvShortlink,
+            importers::{self, Imported, Source},
+            models::{Shortlink, REDACTED},
+            permissions::Permissions,
+            validation::{validate_keyword, validate_shortlink, ValidationErrors}};
+
+/// What to do with a link whose keyword is already taken
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    /// keep the existing link
+    #[default]
+    Skip,
+    /// replace the existing link
+    Overwrite,
+    /// import the link under the first free `keyword-2`, `keyword-3`, …
+    Rename,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportFormat {
+    /// JSON if it looks like an array, CSV otherwise
+    #[default]
+    Auto,
+    Csv,
+    /// an array of links the way the API returns them
+    Json,
+    Yourls,
+    Kutt,
+    Shlink,
+    Bitly,
+    /// links as the first, Rocket based, linkshrink stored them
+    Legacy,
+}
+
+/// What importing one record does, or would do
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum Outcome {
+    Create,
+    Update,
+    Rename {
+        from: String,
+    },
+    Skip {
+        reason: String,
+    },
+    Invalid {
+        #[schema(value_type = HashMap<String, String>)]
+        errors: ValidationErrors,
+    },
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ImportRow {
+    /// position in the file, from 1
+    record: usize,
+    keyword: String,
+    #[serde(flatten)]
+    outcome: Outcome,
+    /// `field: value` for what the other shortener had that linkshrink can't keep
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    unmapped: Vec<String>,
+}
+
+/// What an import did, or with `dry_run` what it would do
+#[derive(Debug, Default, Serialize, ToSchema)]
+pub struct ImportReport {
+    dry_run: bool,
+    created: usize,
+    updated: usize,
+    renamed: usize,
+    skipped: usize,
+    invalid: usize,
+    rows: Vec<ImportRow>,
+}
+
+impl ImportReport {
+    fn add(&mut self, record: usize, keyword: &str, unmapped: Vec<String>, outcome: Outcome) {
+        match outcome {
+            Outcome::Create => self.created += 1,
+            Outcome::Update => self.updated += 1,
+            Outcome::Rename { .. } => self.renamed += 1,
+            Outcome::Skip { .. } => self.skipped += 1,
+            Outcome::Invalid { .. } => self.invalid += 1,
+        }
+        self.rows.push(ImportRow { record,
+                                   keyword: keyword.to_string(),
+                                   outcome,
+                                   unmapped });
+    }
+}
+
+/// every record in `data`, each a link or why it isn't one, unless the file can't be read at all
+fn parse(data: &str, format: ImportFormat) -> Result<Vec<Result<Imported, String>>, String> {
+    let json = match format {
+        ImportFormat::Auto => data.trim_start().starts_with('['),
+        ImportFormat::Csv => false,
+        ImportFormat::Json => true,
+        ImportFormat::Yourls => return importers::parse(data, Source::Yourls),
+        ImportFormat::Kutt => return importers::parse(data, Source::Kutt),
+        ImportFormat::Shlink => return importers::parse(data, Source::Shlink),
+        ImportFormat::Bitly => return importers::parse(data, Source::Bitly),
+        ImportFormat::Legacy => return importers::parse(data, Source::Legacy),
+    };
+
+    if json {
+        let records = serde_json::from_str::<Vec<Value>>(data).map_err(|err| {
+                          format!("Expected a JSON array of links: {err}")
+                      })?;
+        return Ok(records.into_iter()
+                         .map(|record| {
+                             serde_json::from_value::<Shortlink>(record).map(Imported::from)
+                                                                        .map_err(|err| {
+                                                                            err.to_string()
+                                                                        })
+                         })
+                         .collect());
+    }
+
+    let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All)
+                                              .from_reader(data.as_bytes());
+    let headers = reader.headers()
+                        .map_err(|err| format!("Failed to read the CSV header: {err}"))?;
+    if !headers.iter().any(|header| header == "keyword") {
+        return Err("The CSV header needs at least keyword and url columns".to_string());
+    }
+
+    Ok(reader.deserialize::<CsvShortlink>()
+             .map(|row| {
+                 Shortlink::try_from(row.map_err(|err| err.to_string())?).map(Imported::from)
+             })
+             .collect())
+}
+
+/// the first `keyword-n` nobody has
+fn free_keyword(keyword: &str, taken: &HashSet<String>) -> String {
+    (2..).map(|n| format!("{keyword}-{n}"))
+         .find(|candidate| !taken.contains(candidate))
+         .expect("there is always a free keyword")
+}
+
+/// decide what happens to every record, and collect the writes that does
+async fn plan(state: &AppState,
+              request_host: Option<&str>,
+              records: Vec<Result<Imported, String>>,
+              policy: ConflictPolicy)
+              -> Result<(ImportReport, Vec<(Option<Shortlink>, Shortlink)>), DatabaseError> {
+    let config = state.get_config();
+    let existing = state.get_all_shortlinks()
+                        .await?
+                        .into_iter()
+                        .map(|shortlink| (shortlink.keyword.clone(), shortlink))
+                        .collect::<HashMap<_, _>>();
+    let mut taken = existing.keys().cloned().collect::<HashSet<_>>();
+    let mut imported = HashSet::new();
+
+    let mut report = ImportReport::default();
+    let mut writes = vec![];
+    for (index, record) in records.into_iter().enumerate() {
+        let record_number = index + 1;
+        let Imported { mut shortlink,
+                       unmapped, } = match record {
+            Ok(imported) => imported,
+            Err(err) => {
+                let mut errors = ValidationErrors::default();
+                errors.add("record", err);
+                report.add(record_number, "", vec![], Outcome::Invalid { errors });
+                continue;
+            }
+        };
+
+        shortlink.keyword = shortlink.keyword.trim().to_string();
+        // an exported hash is useless, a real one from a backup is kept
+        if shortlink.password_hash.as_deref() == Some(REDACTED) {
+            shortlink.password_hash = None;
+        }
+
+        let mut errors = ValidationErrors::default();
+        validate_shortlink(config, request_host, &mut shortlink, &mut errors);
+        if !errors.is_empty() {
+            report.add(record_number,
+                       &shortlink.keyword,
+                       unmapped,
+                       Outcome::Invalid { errors });
+            continue;
+        }
+
+        let keyword = shortlink.keyword.clone();
+        if !taken.contains(&keyword) {
+            taken.insert(keyword.clone());
+            imported.insert(keyword.clone());
+            report.add(record_number, &keyword, unmapped, Outcome::Create);
+            writes.push((None, shortlink));
+            continue;
+        }
+
+        // two records for the same keyword, the first one wins
+        if imported.contains(&keyword) && policy != ConflictPolicy::Rename {
+            report.add(record_number,
+                       &keyword,
+                       unmapped,
+                       Outcome::Skip { reason:
+                                           "An earlier record has the same keyword".to_string() });
+            continue;
+        }
+
+        match policy {
+            ConflictPolicy::Skip => {
+                report.add(record_number,
+                           &keyword,
+                           unmapped,
+                           Outcome::Skip { reason: "A link with this keyword exists".to_string() })
+            }
+            ConflictPolicy::Overwrite => {
+                let previous = existing.get(&keyword).cloned();
+                // most exports leave hits out, don't lose the ones already counted
+                if shortlink.hits == 0 {
+                    shortlink.hits = previous.as_ref().map_or(0, |previous| previous.hits);
+                }
+                imported.insert(keyword.clone());
+                report.add(record_number, &keyword, unmapped, Outcome::Update);
+                writes.push((previous, shortlink));
+            }
+            ConflictPolicy::Rename => {
+                shortlink.keyword = free_keyword(&keyword, &taken);
+                if let Err(err) = validate_keyword(config, &shortlink.keyword) {
+                    let mut errors = ValidationErrors::default();
+                    errors.add("keyword", err);
+                    report.add(record_number,
+                               &shortlink.keyword,
+                               unmapped,
+                               Outcome::Invalid { errors });
+                    continue;
+                }
+                taken.insert(shortlink.keyword.clone());
+                imported.insert(shortlink.keyword.clone());
+                report.add(record_number,
+                           &shortlink.keyword,
+                           unmapped,
+                           Outcome::Rename { from: keyword });
+                writes.push((None, shortlink));
+            }
+        }
+    }
+
+    Ok((report, writes))
+}
+
+/// plan an import and, unless it's a dry run, carry it out
+async fn run(state: &AppState,
+             actor: &Actor,
+             request_host: Option<&str>,
+             records: Vec<Result<Imported, String>>,
+             policy: ConflictPolicy,
+             dry_run: bool)
+             -> Result<ImportReport, DatabaseError> {
+    let (mut report, writes) = plan(state, request_host, records, policy).await?;
+    report.dry_run = dry_run;
+    if dry_run {
+        return Ok(report);
+    }
+
+    let total = writes.len();
+    let mut writes = writes.into_iter().peekable();
+    while writes.peek().is_some() {
+        let batch = writes.by_ref().take(BATCH_SIZE).collect::<Vec<_>>();
+        let previous = batch.iter()
+                            .map(|(previous, _)| previous.clone())
+                            .collect::<Vec<_>>();
+
+        let saved = state.save_shortlinks(batch).await?;
+        for (previous, saved) in previous.iter().zip(&saved) {
+            let action = match previous {
+                Some(_) => AuditAction::Update,
+                None => AuditAction::Create,
+            };
+            audit::record(state,
+                          actor.event(action, Some(&saved.keyword))
+                               .with_changes(previous.as_ref(), Some(saved))
+                               .with_detail("import")).await;
+        }
+    }
+    info!(total, ?policy, "Imported links");
+
+    Ok(report)
+}
+
+/// the import page
+#[instrument(skip(state, permissions, csrf_token))]
+pub async fn page(State(state): State<AppState>,
+                  permissions: Permissions,
+                  csrf_token: CsrfToken)
+                  -> AppResult<Response> {
+    if let Some(denied) = permissions.require_admin(&state) {
+        return Ok(denied);
+    }
+
+    Ok(RenderHtml("import",
+                  state.get_engine(),
+                  json!({
+                      "csrf_token": csrf_token,
+                      "format": ImportFormat::default(),
+                      "policy": ConflictPolicy::default()
+                  })).into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportForm {
+    data: String,
+    #[serde(default)]
+    format: ImportFormat,
+    #[serde(default)]
+    policy: ConflictPolicy,
+    /// which button was pressed, `preview` or `import`
+    action: String,
+}
+
+#[instrument(skip(state, headers, permissions, actor, csrf_token, form))]
+pub async fn submit(State(state): State<AppState>,
+                    headers: HeaderMap,
+                    permissions: Permissions,
+                    actor: Actor,
+                    csrf_token: CsrfToken,
+                    Form(form): Form<ImportForm>)
+                    -> AppResult<Response> {
+    if let Some(denied) = permissions.require_admin(&state) {
+        return Ok(denied);
+    }
+
+    let request_host = headers.get(HOST).and_then(|host| host.to_str().ok());
+    let (report, error) = match parse(&form.data, form.format) {
+        Ok(records) => {
+            let dry_run = form.action != "import";
+            (Some(run(&state, &actor, request_host, records, form.policy, dry_run).await?), None)
+        }
+        Err(err) => (None, Some(err)),
+    };
+
+    // after a real import there is nothing left to preview
+    let data = match &report {
+        Some(report) if !report.dry_run => String::new(),
+        _ => form.data,
+    };
+
+    Ok(RenderHtml("import",
+                  state.get_engine(),
+                  json!({
+                      "csrf_token": csrf_token,
+                      "data": data,
+                      "format": form.format,
+                      "policy": form.policy,
+                      "report": report,
+                      "error": error
+                  })).into_response())
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ImportQuery {
+    #[serde(default)]
+    format: ImportFormat,
+    #[serde(default)]
+    policy: ConflictPolicy,
+    /// only report what would happen
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// import links sent as the body, CSV or JSON going by `format` or the `Content-Type`, or another
+/// shortener's export
+#[utoipa::path(post,
+               path = "/api/v1/import",
+               tag = "links",
+               params(ImportQuery),
+               request_body(content = String,
+                            content_type = "text/csv",
+                            description = "CSV with a header row, or a JSON array of links, or with `format` set an export from YOURLS, Kutt, Shlink, Bitly or the legacy linkshrink"),
+               responses((status = 200, description = "What was, or would be, imported", body = ImportReport),
+                         (status = 400, description = "The file can't be read", body = ErrorBody),
+                         (status = 403, description = "Only admins can import", body = ErrorBody)))]
+#[instrument(skip(state, headers, permissions, actor, body))]
+pub async fn import_links(State(state): State<AppState>,
+                          headers: HeaderMap,
+                          permissions: Permissions,
+                          actor: Actor,
+                          Query(query): Query<ImportQuery>,
+                          body: String)
+                          -> ApiResult<Json<ImportReport>> {
+    if !permissions.is_admin() {
+        return Err(ApiError::denied(&state, &permissions, "import links"));
+    }
+
+    let content_type = headers.get(CONTENT_TYPE)
+                              .and_then(|content_type| content_type.to_str().ok())
+                              .unwrap_or_default();
+    let format = match query.format {
+        ImportFormat::Auto if content_type.starts_with("text/csv") => ImportFormat::Csv,
+        ImportFormat::Auto if content_type.starts_with("application/json") => ImportFormat::Json,
+        format => format,
+    };
+
+    let records = parse(&body, format).map_err(ApiError::bad_request)?;
+    let request_host = headers.get(HOST).and_then(|host| host.to_str().ok());
+    let report = run(&state,
+                     &actor,
+                     request_host,
+                     records,
+                     query.policy,
+                     query.dry_run).await?;
+
+    Ok(Json(report))
+}
@@ -0,0 +1,430 @@
+use chrono::{DateTime, Utc};
+use serde_json::{Map, Value};
+use std::fmt;
+
+use crate::{formats::parse_timestamp, models::Shortlink};
+
+/// A link read from another shortener's export, with whatever of it had nowhere to go
+#[derive(Debug)]
+pub struct Imported {
+    pub shortlink: Shortlink,
+    /// `field: value` for everything linkshrink can't keep
+    pub unmapped: Vec<String>,
+}
+
+impl From<Shortlink> for Imported {
+    fn from(shortlink: Shortlink) -> Self {
+        Self { shortlink,
+               unmapped: vec![] }
+    }
+}
+
+/// Link shorteners whose exports can be imported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// the `keyword,url,title,timestamp,ip,clicks` export plugins write, or the `list` API
+    Yourls,
+    /// `/api/v2/links`
+    Kutt,
+    /// `/rest/v3/short-urls`, or the CSV the web client exports
+    Shlink,
+    /// `/v4/groups/{group}/bitlinks`
+    Bitly,
+    /// the JSON the first, Rocket based, linkshrink stored
+    Legacy,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+                        Source::Yourls => "YOURLS",
+                        Source::Kutt => "Kutt",
+                        Source::Shlink => "Shlink",
+                        Source::Bitly => "Bitly",
+                        Source::Legacy => "legacy linkshrink",
+                    })
+    }
+}
+
+impl Source {
+    /// where an API response keeps its links, in order of nesting
+    fn list_keys(self) -> &'static [&'static str] {
+        match self {
+            Source::Yourls | Source::Bitly => &["links"],
+            Source::Kutt => &["data"],
+            Source::Shlink => &["shortUrls", "data"],
+            Source::Legacy => &[],
+        }
+    }
+
+    /// fields that only made sense inside the other shortener, not worth reporting
+    fn ignored(self) -> &'static [&'static str] {
+        match self {
+            Source::Yourls => &[],
+            Source::Kutt => &["id", "link", "domain", "created_at"],
+            Source::Shlink => &["shortUrl", "domain", "forwardQuery", "visitsSummary"],
+            Source::Bitly => &["id", "link", "client_id", "references"],
+            Source::Legacy => &["id"],
+        }
+    }
+
+    fn convert(self, mut fields: Fields) -> Imported {
+        let shortlink = match self {
+            Source::Yourls => {
+                let keyword =
+                    fields.string("keyword")
+                          .or_else(|| fields.string("shorturl").map(|url| last_segment(&url)));
+                Shortlink { keyword: keyword.unwrap_or_default(),
+                            url: fields.string("url").unwrap_or_default(),
+                            description: fields.string("title").unwrap_or_default(),
+                            hits: fields.number("clicks").unwrap_or_default() as usize,
+                            modified: fields.time("timestamp"),
+                            ..Default::default() }
+            }
+            Source::Kutt => {
+                Shortlink { keyword: fields.string("address").unwrap_or_default(),
+                            url: fields.string("target").unwrap_or_default(),
+                            description: fields.string("description").unwrap_or_default(),
+                            hits: fields.number("visit_count").unwrap_or_default() as usize,
+                            modified: fields.time("updated_at").or(fields.time("created_at")),
+                            ..Default::default() }
+            }
+            Source::Shlink => {
+                let hits = fields.number("visitsSummary.total")
+                                 .or(fields.number("visitsCount"))
+                                 .or(fields.number("visits"));
+                Shortlink { keyword: fields.string("shortCode").unwrap_or_default(),
+                            url: fields.string("longUrl").unwrap_or_default(),
+                            description: fields.string("title").unwrap_or_default(),
+                            hits: hits.unwrap_or_default() as usize,
+                            modified: fields.time("dateCreated").or(fields.time("createdAt")),
+                            max_uses: fields.number("meta.maxVisits"),
+                            ..Default::default() }
+            }
+            Source::Bitly => {
+                // a custom back-half is what people actually use
+                let custom =
+                    fields.take("custom_bitlinks")
+                          .and_then(|links| links.get(0)?.as_str().map(last_segment));
+                let keyword = custom.or_else(|| fields.string("id").map(|id| last_segment(&id)));
+                Shortlink { keyword: keyword.unwrap_or_default(),
+                            url: fields.string("long_url").unwrap_or_default(),
+                            owner: fields.string("created_by").unwrap_or_default(),
+                            description: fields.string("title").unwrap_or_default(),
+                            hits: fields.number("clicks").unwrap_or_default() as usize,
+                            modified: fields.time("created_at"),
+                            ..Default::default() }
+            }
+            Source::Legacy => {
+                Shortlink { keyword: fields.string("keyword").unwrap_or_default(),
+                            url: fields.string("url").unwrap_or_default(),
+                            owner: fields.string("owner").unwrap_or_default(),
+                            hits: fields.number("hits").unwrap_or_default() as usize,
+                            private: fields.flag("private").unwrap_or_default(),
+                            description: fields.string("description").unwrap_or_default(),
+                            ..Default::default() }
+            }
+        };
+
+        Imported { shortlink,
+                   unmapped: fields.unmapped(self.ignored()) }
+    }
+
+    /// convert one record of an export
+    pub fn import(self, record: Value) -> Result<Imported, String> {
+        Ok(self.convert(Fields::new(record)?))
+    }
+}
+
+/// The fields of one exported record, nested objects flattened to `outer.inner`, which are taken
+/// out as they are mapped so the rest can be reported
+struct Fields(Map<String, Value>);
+
+impl Fields {
+    fn new(record: Value) -> Result<Self, String> {
+        let Value::Object(record) = record else {
+            return Err("Expected an object for each link".to_string());
+        };
+
+        let mut fields = Map::new();
+        flatten("", record, &mut fields);
+        Ok(Self(fields))
+    }
+
+    fn take(&mut self, name: &str) -> Option<Value> {
+        self.0.remove(name).filter(says_something)
+    }
+
+    /// a value that doesn't parse stays behind to be reported
+    fn parsed<T>(&mut self, name: &str, parse: impl FnOnce(&Value) -> Option<T>) -> Option<T> {
+        let value = self.take(name)?;
+        let parsed = parse(&value);
+        if parsed.is_none() {
+            self.0.insert(name.to_string(), value);
+        }
+        parsed
+    }
+
+    fn string(&mut self, name: &str) -> Option<String> {
+        self.parsed(name, |value| match value {
+                Value::String(text) => Some(text.trim().to_string()),
+                Value::Number(number) => Some(number.to_string()),
+                _ => None,
+            })
+    }
+
+    /// numbers come as strings from CSV, and from some APIs too
+    fn number(&mut self, name: &str) -> Option<u64> {
+        self.parsed(name, |value| {
+                value.as_u64()
+                     .or_else(|| value.as_str()?.trim().parse().ok())
+            })
+    }
+
+    fn flag(&mut self, name: &str) -> Option<bool> {
+        self.parsed(name, |value| {
+                value.as_bool().or_else(|| {
+                                   match value.as_str()?.trim().to_ascii_lowercase().as_str() {
+                                       "true" | "1" | "yes" => Some(true),
+                                       "false" | "0" | "no" => Some(false),
+                                       _ => None,
+                                   }
+                               })
+            })
+    }
+
+    fn time(&mut self, name: &str) -> Option<DateTime<Utc>> {
+        self.parsed(name, |value| parse_timestamp(value.as_str()?.trim()))
+    }
+
+    /// whatever nothing took
+    fn unmapped(self, ignored: &[&str]) -> Vec<String> {
+        self.0
+            .into_iter()
+            .filter(|(name, value)| {
+                says_something(value)
+                && !ignored.iter().any(|ignored| {
+                                      name == ignored || name.starts_with(&format!("{ignored}."))
+                                  })
+            })
+            .map(|(name, value)| match value {
+                Value::String(text) => format!("{name}: {text}"),
+                value => format!("{name}: {value}"),
+            })
+            .collect()
+    }
+}
+
+fn flatten(prefix: &str, object: Map<String, Value>, fields: &mut Map<String, Value>) {
+    for (name, value) in object {
+        let name = match prefix {
+            "" => name,
+            prefix => format!("{prefix}.{name}"),
+        };
+        match value {
+            Value::Object(inner) => flatten(&name, inner, fields),
+            value => {
+                fields.insert(name, value);
+            }
+        }
+    }
+}
+
+/// empty and switched off fields are left out rather than reported
+fn says_something(value: &Value) -> bool {
+    match value {
+        Value::Null | Value::Bool(false) => false,
+        Value::String(text) => !text.trim().is_empty(),
+        Value::Array(values) => !values.is_empty(),
+        Value::Object(fields) => !fields.is_empty(),
+        _ => true,
+    }
+}
+
+/// the keyword of a short url
+fn last_segment(url: &str) -> String {
+    url.trim_end_matches('/')
+       .rsplit('/')
+       .next()
+       .unwrap_or_default()
+       .to_string()
+}
+
+/// every record in an export, JSON if it looks like it and CSV with a header row otherwise
+pub fn parse(data: &str, source: Source) -> Result<Vec<Result<Imported, String>>, String> {
+    let records = match data.trim_start().starts_with(['[', '{']) {
+        true => json_records(data, source)?.into_iter().map(Ok).collect(),
+        false => csv_records(data)?,
+    };
+
+    Ok(records.into_iter()
+              .map(|record| source.import(record?))
+              .collect())
+}
+
+fn json_records(data: &str, source: Source) -> Result<Vec<Value>, String> {
+    let mut value = serde_json::from_str::<Value>(data).map_err(|err| {
+                                                           format!("Failed to read the JSON: {err}")
+                                                       })?;
+    for key in source.list_keys() {
+        if let Some(inner) = value.get_mut(*key).map(Value::take) {
+            value = inner;
+        }
+    }
+
+    match value {
+        Value::Array(records) => Ok(records),
+        // YOURLS lists links by their position, a dump of the store by their keyword
+        Value::Object(records) if records.values().all(Value::is_object) => {
+            Ok(records.into_iter().map(|(_, record)| record).collect())
+        }
+        _ => Err(format!("Expected a JSON list of {source} links")),
+    }
+}
+
+/// rows as objects of strings, a broken row is only an error for that row
+fn csv_records(data: &str) -> Result<Vec<Result<Value, String>>, String> {
+    let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All)
+                                              .from_reader(data.as_bytes());
+    let headers = reader.headers()
+                        .map_err(|err| format!("Failed to read the CSV header: {err}"))?
+                        .clone();
+
+    Ok(reader.records()
+             .map(|row| {
+                 let row = row.map_err(|err| err.to_string())?;
+                 Ok(Value::Object(headers.iter()
+                                         .zip(row.iter())
+                                         .map(|(header, value)| {
+                                             (header.to_string(), Value::String(value.to_string()))
+                                         })
+                                         .collect()))
+             })
+             .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn import(source: Source, record: Value) -> Imported {
+        source.import(record).unwrap()
+    }
+
+    fn time(text: &str) -> Option<DateTime<Utc>> {
+        Some(DateTime::parse_from_rfc3339(text).unwrap()
+                                               .with_timezone(&Utc))
+    }
+
+    #[test]
+    fn yourls_csv_rows() {
+        let records = parse("keyword,url,title,timestamp,ip,clicks\n\
+                             docs,https://example.com/docs,Docs,2023-04-01 12:30:00,10.0.0.1,7\n",
+                            Source::Yourls).unwrap();
+        let imported = records.into_iter().next().unwrap().unwrap();
+
+        assert_eq!(imported.shortlink.keyword, "docs");
+        assert_eq!(imported.shortlink.url, "https://example.com/docs");
+        assert_eq!(imported.shortlink.description, "Docs");
+        assert_eq!(imported.shortlink.hits, 7);
+        assert_eq!(imported.shortlink.modified, time("2023-04-01T12:30:00Z"));
+        assert_eq!(imported.unmapped, ["ip: 10.0.0.1"]);
+    }
+
+    #[test]
+    fn yourls_keyword_from_short_url() {
+        let imported = import(Source::Yourls,
+                              json!({"shorturl": "https://sho.rt/wiki/", "url": "https://wiki"}));
+        assert_eq!(imported.shortlink.keyword, "wiki");
+    }
+
+    #[test]
+    fn kutt_links() {
+        let imported = import(Source::Kutt,
+                              json!({"id": "1b2c", "address": "team", "target": "https://team",
+                                     "description": "Team page", "visit_count": 3,
+                                     "password": true, "expire_in": "2030-01-01T00:00:00Z",
+                                     "created_at": "2023-01-01T00:00:00Z",
+                                     "updated_at": "2023-02-01T00:00:00Z"}));
+
+        assert_eq!(imported.shortlink.keyword, "team");
+        assert_eq!(imported.shortlink.url, "https://team");
+        assert_eq!(imported.shortlink.description, "Team page");
+        assert_eq!(imported.shortlink.hits, 3);
+        assert_eq!(imported.shortlink.modified, time("2023-02-01T00:00:00Z"));
+        assert_eq!(imported.unmapped,
+                   ["expire_in: 2030-01-01T00:00:00Z", "password: true"]);
+    }
+
+    #[test]
+    fn shlink_links() {
+        let imported = import(Source::Shlink,
+                              json!({"shortCode": "abc", "longUrl": "https://long",
+                                     "title": null, "dateCreated": "2023-03-01T10:00:00+02:00",
+                                     "visitsSummary": {"total": 12, "nonBots": 10},
+                                     "meta": {"maxVisits": 100, "validUntil": null},
+                                     "tags": ["ops"]}));
+
+        assert_eq!(imported.shortlink.keyword, "abc");
+        assert_eq!(imported.shortlink.url, "https://long");
+        assert_eq!(imported.shortlink.hits, 12);
+        assert_eq!(imported.shortlink.max_uses, Some(100));
+        assert_eq!(imported.shortlink.modified, time("2023-03-01T08:00:00Z"));
+        assert_eq!(imported.unmapped, [r#"tags: ["ops"]"#]);
+    }
+
+    #[test]
+    fn bitly_links_prefer_the_custom_back_half() {
+        let imported = import(Source::Bitly,
+                              json!({"id": "bit.ly/3xYz", "long_url": "https://long",
+                                     "custom_bitlinks": ["https://bit.ly/launch"],
+                                     "created_by": "alice", "title": "Launch",
+                                     "created_at": "2023-05-01T09:00:00+0000",
+                                     "tags": []}));
+
+        assert_eq!(imported.shortlink.keyword, "launch");
+        assert_eq!(imported.shortlink.url, "https://long");
+        assert_eq!(imported.shortlink.owner, "alice");
+        assert_eq!(imported.shortlink.description, "Launch");
+        assert_eq!(imported.shortlink.modified, time("2023-05-01T09:00:00Z"));
+        assert!(imported.unmapped.is_empty());
+
+        let imported = import(Source::Bitly,
+                              json!({"id": "bit.ly/3xYz", "long_url": "https://long"}));
+        assert_eq!(imported.shortlink.keyword, "3xYz");
+    }
+
+    #[test]
+    fn legacy_links() {
+        let imported = import(Source::Legacy,
+                              json!({"id": 4, "keyword": "old", "url": "https://old",
+                                     "owner": "bob", "hits": null, "private": "yes"}));
+
+        assert_eq!(imported.shortlink.keyword, "old");
+        assert_eq!(imported.shortlink.url, "https://old");
+        assert_eq!(imported.shortlink.owner, "bob");
+        assert_eq!(imported.shortlink.hits, 0);
+        assert!(imported.shortlink.private);
+        assert!(imported.unmapped.is_empty());
+    }
+
+    #[test]
+    fn values_that_dont_parse_are_reported() {
+        let imported = import(Source::Kutt,
+                              json!({"address": "x", "target": "https://x",
+                                     "visit_count": "many"}));
+
+        assert_eq!(imported.shortlink.hits, 0);
+        assert_eq!(imported.unmapped, ["visit_count: many"]);
+    }
+
+    #[test]
+    fn api_responses_are_unwrapped() {
+        let data = r#"{"shortUrls": {"data": [{"shortCode": "a", "longUrl": "https://a"}]}}"#;
+        let records = parse(data, Source::Shlink).unwrap();
+
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].as_ref().unwrap().shortlink.keyword, "a");
+    }
+}
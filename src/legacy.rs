@@ -0,0 +1,131 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use tracing::info;
+
+use crate::{database::{AppState, DatabaseError, BATCH_SIZE},
+            importers::{Imported, Source}};
+
+/// What moving links out of the first linkshrink's bare keyword keys did, or would do
+#[derive(Serialize, Debug, Default)]
+pub struct MigrationReport {
+    dry_run: bool,
+    /// keys without a `prefix::`, of which only the ones holding a link are ours
+    scanned: usize,
+    migrated: Vec<String>,
+    /// moved by an earlier run that stopped before removing the old key, which is removed now
+    already_migrated: Vec<String>,
+    /// left where they are for someone to sort out
+    conflicts: Vec<Conflict>,
+    /// what migrated links had that linkshrink no longer keeps, by keyword
+    unmapped: BTreeMap<String, Vec<String>>,
+}
+
+impl MigrationReport {
+    /// for the audit log
+    pub fn summary(&self) -> String {
+        format!("{} migrated, {} already migrated, {} conflicts",
+                self.migrated.len(),
+                self.already_migrated.len(),
+                self.conflicts.len())
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Conflict {
+    key: String,
+    reason: String,
+}
+
+/// the link a bare key holds, if it holds one the way the first linkshrink stored them
+fn legacy_link(value: &str) -> Option<Value> {
+    let value = serde_json::from_str::<Value>(value).ok()?;
+    let is_link = value.get("keyword").is_some_and(Value::is_string)
+                  && value.get("url").is_some_and(Value::is_string);
+    is_link.then_some(value)
+}
+
+/// move every link stored under its bare keyword to `sl::{keyword}` and remove the old key, links
+/// whose keyword is taken by a different link are left alone and reported, so running it again
+/// only picks up what's left
+pub async fn migrate(state: &AppState, dry_run: bool) -> Result<MigrationReport, DatabaseError> {
+    let keys = state.scan_keys("*")
+                    .await?
+                    .into_iter()
+                    .filter(|key| !key.contains("::"))
+                    .collect::<Vec<_>>();
+
+    let mut report = MigrationReport { dry_run,
+                                       scanned: keys.len(),
+                                       ..Default::default() };
+    let mut writes = vec![];
+    let mut done = vec![];
+    for batch in keys.chunks(BATCH_SIZE) {
+        let values = state.get_strings(batch).await?;
+        for (key, value) in batch.iter().zip(values) {
+            let Some(link) = value.as_deref().and_then(legacy_link) else {
+                continue;
+            };
+
+            let Imported { shortlink,
+                           unmapped, } = match Source::Legacy.import(link) {
+                Ok(imported) => imported,
+                Err(reason) => {
+                    report.conflicts.push(Conflict { key: key.clone(),
+                                                     reason });
+                    continue;
+                }
+            };
+
+            if &shortlink.keyword != key {
+                report.conflicts.push(Conflict { key: key.clone(),
+                                                 reason: format!("Holds the link {:?}",
+                                                                 shortlink.keyword) });
+                continue;
+            }
+
+            match state.get_shortlink(key).await? {
+                Some(existing) if existing.url == shortlink.url => {
+                    report.already_migrated.push(key.clone());
+                    done.push(key.clone());
+                }
+                Some(existing) => {
+                    report.conflicts
+                          .push(Conflict { key: key.clone(),
+                                           reason:
+                                               format!("The keyword is taken by a link to {}",
+                                                       existing.url) });
+                }
+                None => {
+                    if !unmapped.is_empty() {
+                        report.unmapped.insert(key.clone(), unmapped);
+                    }
+                    report.migrated.push(key.clone());
+                    writes.push(shortlink);
+                    done.push(key.clone());
+                }
+            }
+        }
+    }
+
+    if dry_run {
+        return Ok(report);
+    }
+
+    // the new keys are written before any old one goes, so a failed run loses nothing
+    for batch in writes.chunks(BATCH_SIZE) {
+        let changes = batch.iter()
+                           .cloned()
+                           .map(|shortlink| (None, shortlink))
+                           .collect();
+        state.save_shortlinks(changes).await?;
+    }
+    for key in &done {
+        state.delete_key(key).await?;
+    }
+    info!(migrated = report.migrated.len(),
+          conflicts = report.conflicts.len(),
+          "Migrated legacy links");
+
+    Ok(report)
+}
@@ -1,78 +1,170 @@
+mod admin;
+mod api;
+mod audit;
+mod auth;
+mod backup;
+mod cli;
+mod config;
+mod csrf;
 mod database;
 mod error;
+mod formats;
+mod import;
+mod importers;
+mod legacy;
 mod models;
+mod oidc;
+mod openapi;
+mod permissions;
+mod ratelimit;
+mod routing;
+mod rules;
+mod schema;
+mod tokens;
+mod validation;
 
-use error::AppResult;
+use error::{error_page, AppResult};
 
-use axum::{extract::{Path, State},
-           http::{header::HeaderMap, StatusCode},
-           response::{IntoResponse, Redirect},
-           routing::{get, post},
-           Form, Router, Server};
+use axum::{extract::{ConnectInfo, DefaultBodyLimit, Path, State},
+           http::{header::{HeaderMap, CACHE_CONTROL, HOST, LOCATION, SET_COOKIE},
+                  StatusCode},
+           middleware,
+           response::{IntoResponse, Redirect, Response},
+           routing::{get, post, MethodRouter},
+           Form, Json, Router, Server};
 
-use axum_prometheus::PrometheusMetricLayer;
+use axum_prometheus::{metrics_exporter_prometheus::PrometheusHandle, PrometheusMetricLayer};
 
 use axum_template::RenderHtml;
 
+use chrono::Utc;
 use serde::Deserialize;
 use serde_json::json;
+use utoipa::ToSchema;
 
-use crate::database::AppState;
-use tracing::{debug, instrument};
+use crate::{api::ApiError,
+            audit::{Actor, AuditAction},
+            auth::client_ip,
+            config::Config,
+            csrf::CsrfToken,
+            database::AppState,
+            formats::{co_owners_text, destinations_text, parse_co_owners, parse_destinations,
+                      parse_rules, rules_text, Format},
+            models::{RedirectKind, Shortlink},
+            permissions::Permissions,
+            routing::Target,
+            validation::{domain_violation, reserved_by_routes, validate_keyword,
+                         validate_shortlink, validate_url, ValidationErrors}};
+use std::net::SocketAddr;
+use tracing::{debug, instrument, warn};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-
+use url::{form_urlencoded, Url};
 
 #[tokio::main]
 async fn main() -> AppResult<()> {
-    let redis_host = &std::env::var("LINKSHRINK_REDIS_HOST").unwrap_or_else(|_| "127.0.0.1".to_string());
-    let redis_port = std::env::var("LINKSHRINK_REDIS_PORT")
-                             .unwrap_or_else(|_| "6379".to_string())
-                             .parse::<u16>()
-                             .unwrap_or(6379);
-
-    let listen_host = &std::env::var("LINKSHRINK_LISTEN_HOST").unwrap_or_else(|_| "127.0.0.1".to_string());
-    let listen_port = std::env::var("LINKSHRINK_LISTEN_PORT")
-                             .unwrap_or_else(|_| "8080".to_string())
-                             .parse::<u16>()
-                             .unwrap_or(8080);
+    let mut config = Config::from_env();
+    let listen_address = format!("{}:{}", config.listen_host, config.listen_port);
 
     tracing_subscriber::registry().with(tracing_subscriber::EnvFilter::from_default_env()
                                   .add_directive("handlebars=info".parse().unwrap())
                                   .add_directive("hyper=info".parse().unwrap()))
                                   .with(tracing_subscriber::fmt::layer())
                                   .init();
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if cli::run(config.clone(), &args).await {
+        return Ok(());
+    }
+
     let (prom_layer, metrics_handler) = PrometheusMetricLayer::pair();
 
-    let database = AppState::new(redis_host, redis_port).await?;
+    if config.secret.is_generated() {
+        warn!("LINKSHRINK_SECRET is not set, forms opened before a restart or on another instance will be rejected");
+    }
+
+    let routes = routes(metrics_handler);
+    config.reserved_keywords
+          .extend(reserved_by_routes(routes.iter().map(|(path, _)| *path)));
+    debug!(reserved_keywords = ?config.reserved_keywords);
+
+    let rewrite_old_records = config.rewrite_old_records;
+    let database = AppState::new(config).await?;
+    // broken records only leave gaps in listings, but someone should hear about them
+    schema::check(&database, rewrite_old_records).await?;
 
-    let app = Router::new().route("/", get(root))
-                           .route("/favicon.ico", get(favicon))
-                           .route("/links", get(get_all_links))
-                           .route("/edit/:keyword", get(edit_keyword))
-                           .route("/edit/:keyword", post(update_keyword))
-                           .route("/:keyword", get(get_keyword))
-                           .route("/metrics", get(|| async move { metrics_handler.render() }))
-                           .layer(prom_layer)
-                           .with_state(database);
+    let app = routes.into_iter()
+                    .fold(Router::new(), |router, (path, method_router)| {
+                        router.route(path, method_router)
+                    })
+                    // innermost first, the CSRF check and rate limits need to know who is asking
+                    .layer(middleware::from_fn_with_state(database.clone(), csrf::protect))
+                    .layer(middleware::from_fn_with_state(database.clone(), ratelimit::limit))
+                    .layer(middleware::from_fn_with_state(database.clone(), auth::authenticate))
+                    .layer(prom_layer)
+                    .with_state(database);
 
-    Server::bind(&format!("{listen_host}:{listen_port}").parse().unwrap()).serve(app.into_make_service())
+    let service = app.into_make_service_with_connect_info::<SocketAddr>();
+    Server::bind(&listen_address.parse().unwrap()).serve(service)
                                                   .await
                                                   .unwrap();
 
     Ok(())
 }
 
-#[instrument]
-async fn root(headers: HeaderMap) -> impl IntoResponse {
-    let addr = headers.get("X-Real-IP")
-                      .or_else(|| headers.get("X-Forwarded-For"))
-                      .and_then(|ip| ip.to_str().ok())
-                      .unwrap_or("unknown");
+/// every route we serve, `/:keyword` has to stay last and must not be able to shadow any of the others
+fn routes(metrics_handler: PrometheusHandle) -> Vec<(&'static str, MethodRouter<AppState>)> {
+    vec![("/", get(root)),
+         ("/favicon.ico", get(favicon)),
+         ("/links", get(get_all_links)),
+         ("/links/violations", get(get_violations)),
+         ("/edit/:keyword", get(edit_keyword).post(update_keyword)),
+         ("/edit/:keyword/delete", post(delete_keyword)),
+         ("/preview/:keyword", get(preview_keyword)),
+         ("/auth/login", get(oidc::login)),
+         ("/auth/callback", get(oidc::callback)),
+         ("/auth/logout", get(oidc::logout)),
+         ("/settings/tokens", get(tokens::list).post(tokens::create)),
+         ("/settings/tokens/revoke", post(tokens::revoke)),
+         ("/admin", get(admin::index)),
+         ("/admin/transfer", post(admin::transfer)),
+         ("/admin/trash", get(admin::trash)),
+         ("/admin/audit", get(audit::page)),
+         ("/admin/audit.json", get(audit::events)),
+         ("/admin/trash/restore", post(admin::restore)),
+         ("/admin/trash/purge", post(admin::purge)),
+         ("/admin/import", get(import::page).post(import::submit)),
+         ("/admin/backup",
+          get(backup::download).post(backup::upload)
+                               .layer(DefaultBodyLimit::max(backup::BACKUP_BODY_LIMIT))),
+         ("/admin/backup/restore",
+          post(backup::restore_form).layer(DefaultBodyLimit::max(backup::BACKUP_BODY_LIMIT))),
+         ("/api/openapi.json", get(openapi::spec)),
+         ("/api/docs", get(openapi::docs)),
+         ("/api/v1/import", post(import::import_links)),
+         ("/api/v1/links", get(api::list_links).post(api::create_link)),
+         ("/api/v1/links/:keyword",
+          get(api::get_link).put(api::replace_link)
+                            .patch(api::update_link)
+                            .delete(api::delete_link)),
+         ("/metrics", get(|| async move { metrics_handler.render() })),
+         ("/:keyword", get(get_keyword).post(unlock_keyword))]
+}
+
+#[instrument(skip(state, permissions))]
+async fn root(State(state): State<AppState>,
+              ConnectInfo(peer): ConnectInfo<SocketAddr>,
+              permissions: Permissions,
+              headers: HeaderMap)
+              -> Response {
+    if let Some(denied) = permissions.require_admin(&state) {
+        return denied;
+    }
+
+    let addr = client_ip(state.get_config(), peer, &headers);
     let user_agent = headers.get("User-Agent")
                             .and_then(|ua| ua.to_str().ok())
                             .unwrap_or("User agent unknown");
 
-    format!("hello, {addr}.\n\nUA:{user_agent}.\n\n\nHeaders Map: {headers:#?}")
+    format!("hello, {addr}.\n\nUA:{user_agent}.\n\n\nHeaders Map: {headers:#?}").into_response()
 }
 
 #[instrument]
@@ -81,79 +173,554 @@ async fn favicon() -> impl IntoResponse {
                                              .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
 }
 
-#[instrument(skip(state))]
-async fn get_all_links(State(state): State<AppState>) -> AppResult<impl IntoResponse> {
+#[instrument(skip(state, permissions))]
+async fn get_all_links(State(state): State<AppState>,
+                       permissions: Permissions,
+                       format: Format)
+                       -> AppResult<Response> {
+    // private links never leave the server for people who may not see them
+    let shortlinks = state.get_all_shortlinks()
+                          .await?
+                          .into_iter()
+                          .filter(|shortlink| permissions.can_view(shortlink))
+                          .map(|shortlink| permissions.redact(shortlink))
+                          .collect::<Vec<_>>();
+
+    let response = match format {
+        Format::Json => Json(shortlinks).into_response(),
+        Format::Csv => formats::csv(&shortlinks)?,
+        Format::Html => RenderHtml("links",
+                                   state.get_engine(),
+                                   json!({
+                                       "admin": permissions.is_admin(),
+                                       "shortlinks": shortlinks
+                                   })).into_response(),
+    };
+
+    Ok(formats::vary(response))
+}
+
+/// links that break the url policy, e.g. after a domain was added to the deny list
+#[instrument(skip(state, permissions))]
+async fn get_violations(State(state): State<AppState>,
+                        permissions: Permissions)
+                        -> AppResult<Response> {
+    if let Some(denied) = permissions.require_admin(&state) {
+        return Ok(denied);
+    }
+
     let shortlinks = state.get_all_shortlinks().await?;
+    let config = state.get_config();
 
-    Ok(RenderHtml("links",
+    let mut violations = vec![];
+    for shortlink in &shortlinks {
+        for url in shortlink.urls() {
+            if let Err(problem) = validate_url(config, None, url) {
+                violations.push(json!({
+                                    "keyword": shortlink.keyword,
+                                    "owner": shortlink.owner,
+                                    "url": url,
+                                    "problem": problem
+                                }));
+            }
+        }
+    }
+
+    Ok(RenderHtml("violations",
                   state.get_engine(),
-                  json!({ "shortlinks": shortlinks })))
+                  json!({ "violations": violations })).into_response())
 }
 
-#[instrument(skip(state))]
+#[instrument(skip(state, permissions, csrf_token))]
 async fn edit_keyword(State(state): State<AppState>,
-                      Path(keyword): Path<String>)
-                      -> AppResult<impl IntoResponse> {
-    let mut shortlink = state.get_shortlink(&keyword).await?.unwrap_or_default();
+                      Path(keyword): Path<String>,
+                      permissions: Permissions,
+                      csrf_token: CsrfToken,
+                      format: Format)
+                      -> AppResult<Response> {
+    let existing = state.get_shortlink(&keyword).await?;
+
+    // scripts get the link itself, there is no form to fill in for them
+    if format != Format::Html {
+        let response = match existing {
+            Some(existing) if permissions.can_view(&existing) => match format {
+                Format::Csv => formats::csv([&permissions.redact(existing)])?,
+                _ => Json(permissions.redact(existing)).into_response(),
+            },
+            _ => ApiError::not_found(&keyword).into_response(),
+        };
+        return Ok(formats::vary(response));
+    }
+
+    if let Some(existing) = &existing {
+        // the same as the JSON above, a private link nobody can be told apart for isn't there
+        if !permissions.can_view(existing) {
+            return Ok(no_such_link(&state, &keyword));
+        }
+        if !permissions.can_edit(existing) {
+            return Ok(permissions.denied(&state, &format!("change {keyword}")));
+        }
+    }
+
+    let mut shortlink = existing.unwrap_or_default();
 
     shortlink.keyword = keyword;
     debug!("edit shortlink: {:?}", shortlink.keyword);
 
-    Ok(RenderHtml("edit",
-                  state.get_engine(),
-                  json!({
-                      "create": shortlink.url.is_empty(),
-                      "shortlink": shortlink
-                  })))
+    let mut errors = ValidationErrors::default();
+    if let Err(err) = validate_keyword(state.get_config(), &shortlink.keyword) {
+        errors.add("keyword", err);
+    }
+
+    let create = shortlink.url.is_empty();
+    let manages = permissions.can_manage(&shortlink);
+    let uses = match shortlink.max_uses {
+        Some(_) => state.get_uses(&shortlink.keyword).await?,
+        None => 0,
+    };
+
+    let page = RenderHtml("edit",
+                          state.get_engine(),
+                          json!({
+                              "create": create,
+                              "manages": manages,
+                              "user": permissions.identity(),
+                              "login": state.get_oidc().is_some(),
+                              "errors": errors,
+                              "destinations": destinations_text(&shortlink.destinations),
+                              "rules": rules_text(&shortlink.rules),
+                              "co_owners": co_owners_text(&shortlink.co_owners),
+                              "max_uses": shortlink.max_uses.map(|max_uses| max_uses.to_string()),
+                              "uses": uses,
+                              "csrf_token": csrf_token,
+                              "shortlink": shortlink.redacted()
+                          }));
+
+    Ok(formats::vary(page.into_response()))
 }
 
-#[derive(Deserialize, Debug)]
+/// What the edit page posts
+#[derive(Deserialize, Debug, ToSchema)]
 struct UpdateForm {
     url: String,
     owner: String,
     description: String,
     #[serde(default)]
     private: bool,
+    #[serde(default)]
+    redirect_kind: RedirectKind,
+    /// one `weight url` per line
+    #[serde(default)]
+    destinations: String,
+    #[serde(default)]
+    sticky: bool,
+    /// one `condition => url` per line
+    #[serde(default)]
+    rules: String,
+    /// comma separated user names
+    #[serde(default)]
+    co_owners: String,
+    /// a new passphrase, left empty to keep the current one
+    #[serde(default)]
+    password: String,
+    #[serde(default)]
+    remove_password: bool,
+    /// empty for unlimited
+    #[serde(default)]
+    max_uses: String,
 }
 
-#[instrument(skip(state))]
+#[utoipa::path(post,
+               path = "/edit/{keyword}",
+               tag = "pages",
+               params(("keyword" = String, Path, description = "the shortlink's keyword")),
+               request_body(content = UpdateForm,
+                            content_type = "application/x-www-form-urlencoded",
+                            description = "Also needs the `csrf_token` of the edit page, or an API token"),
+               responses((status = 200, description = "The saved link, as a page", content_type = "text/html"),
+                         (status = 422, description = "The form again, with what's wrong", content_type = "text/html")))]
+#[instrument(skip(state, permissions, actor, csrf_token))]
 async fn update_keyword(State(state): State<AppState>,
                         Path(keyword): Path<String>,
+                        headers: HeaderMap,
+                        permissions: Permissions,
+                        actor: Actor,
+                        csrf_token: CsrfToken,
                         Form(form): Form<UpdateForm>)
-                        -> AppResult<impl IntoResponse> {
-    let mut existing = state.get_shortlink(&keyword).await?.unwrap_or_default();
+                        -> AppResult<Response> {
+    let existing = state.get_shortlink(&keyword).await?;
+    let create = existing.is_none();
+
+    if existing.as_ref()
+               .is_some_and(|existing| !permissions.can_view(existing))
+    {
+        return Ok(no_such_link(&state, &keyword));
+    }
+
+    let allowed = match &existing {
+        Some(existing) => permissions.can_edit(existing),
+        None => permissions.can_create(),
+    };
+    if !allowed {
+        return Ok(permissions.denied(&state, &format!("change {keyword}")));
+    }
+
+    let mut updated = existing.clone().unwrap_or_default();
+    let mut errors = ValidationErrors::default();
+
+    let manages = permissions.can_manage(&updated);
+
+    updated.keyword = keyword;
+    updated.url = form.url.trim().to_string();
+    updated.private = form.private;
+    // with authentication the owner is whoever created the link, not whatever the form says
+    updated.owner = match permissions.identity() {
+        Some(identity) if create || updated.owner.is_empty() => identity.user.clone(),
+        Some(_) if manages && !form.owner.trim().is_empty() => form.owner.trim().to_string(),
+        Some(_) => updated.owner,
+        // only without auth, where the form is all there is to go by
+        None => form.owner.clone(),
+    };
+    if manages {
+        updated.co_owners = parse_co_owners(&form.co_owners);
+    }
+    updated.description = form.description.clone();
+    updated.redirect_kind = form.redirect_kind;
+    updated.destinations = match parse_destinations(&form.destinations, &updated.destinations) {
+        Ok(destinations) => destinations,
+        Err(err) => {
+            errors.add("destinations", err);
+            vec![]
+        }
+    };
+    updated.sticky = form.sticky;
+    updated.rules = match parse_rules(&form.rules) {
+        Ok(rules) => rules,
+        Err(err) => {
+            errors.add("rules", err);
+            vec![]
+        }
+    };
+    updated.max_uses = match parse_max_uses(&form.max_uses) {
+        Ok(max_uses) => max_uses,
+        Err(err) => {
+            errors.add("max_uses", err);
+            None
+        }
+    };
 
-    existing.keyword = keyword;
-    existing.url = form.url;
-    existing.private = form.private;
-    existing.owner = form.owner;
-    existing.description = form.description;
+    let request_host = headers.get(HOST).and_then(|host| host.to_str().ok());
+    validate_shortlink(state.get_config(), request_host, &mut updated, &mut errors);
 
-    state.store_shortlink(existing.clone()).await?;
+    if !errors.is_empty() {
+        // send the form back as typed so nothing is lost
+        return Ok((StatusCode::UNPROCESSABLE_ENTITY,
+                   RenderHtml("edit",
+                              state.get_engine(),
+                              json!({
+                                  "create": create,
+                                  "manages": manages,
+                                  "user": permissions.identity(),
+                                  "login": state.get_oidc().is_some(),
+                                  "errors": errors,
+                                  "destinations": form.destinations,
+                                  "rules": form.rules,
+                                  "co_owners": form.co_owners,
+                                  "max_uses": form.max_uses,
+                                  "csrf_token": csrf_token,
+                                  "shortlink": updated.redacted()
+                              })))
+                                  .into_response());
+    }
+
+    if form.remove_password {
+        updated.password_hash = None;
+    } else if !form.password.is_empty() {
+        updated.set_password(&form.password)
+               .map_err(anyhow::Error::msg)?;
+    }
+
+    let saved = state.save_shortlink(existing.as_ref(), updated).await?;
+
+    let action = match create {
+        true => AuditAction::Create,
+        false => AuditAction::Update,
+    };
+    audit::record(&state,
+                  actor.event(action, Some(&saved.keyword))
+                       .with_changes(existing.as_ref(), Some(&saved))).await;
 
     Ok(RenderHtml("edit",
                   state.get_engine(),
                   json!({
                       "saved": true,
-                      "shortlink": existing
-                  })))
+                      "shortlink": saved.redacted()
+                  })).into_response())
 }
 
-#[instrument(skip(state))]
-async fn get_keyword(State(state): State<AppState>,
-                     Path(keyword): Path<String>)
-                     -> AppResult<impl IntoResponse> {
+/// move a link to the trash, its owner or an admin can do this
+#[instrument(skip(state, permissions, actor))]
+async fn delete_keyword(State(state): State<AppState>,
+                        Path(keyword): Path<String>,
+                        permissions: Permissions,
+                        actor: Actor)
+                        -> AppResult<Response> {
+    let Some(shortlink) = state.get_shortlink(&keyword).await? else {
+        return Ok(no_such_link(&state, &keyword));
+    };
+
+    if !permissions.can_view(&shortlink) {
+        return Ok(no_such_link(&state, &keyword));
+    }
+    if !permissions.can_manage(&shortlink) {
+        return Ok(permissions.denied(&state, &format!("delete {keyword}")));
+    }
+
+    let event = actor.event(AuditAction::Delete, Some(&keyword))
+                     .with_changes(Some(&shortlink), None);
+    let deleted_by = permissions.identity().map(|identity| identity.user.clone());
+    state.trash_shortlink(shortlink, deleted_by).await?;
+    audit::record(&state, event).await;
+
+    Ok(Redirect::to("/links").into_response())
+}
+
+/// also what links are when this user may not know they exist
+fn no_such_link(state: &AppState, keyword: &str) -> Response {
+    error_page(state.get_engine(),
+               StatusCode::NOT_FOUND,
+               format!("There is no link called {keyword}"))
+}
+
+/// empty for no limit
+fn parse_max_uses(input: &str) -> Result<Option<u64>, String> {
+    match input.trim() {
+        "" => Ok(None),
+        uses => uses.parse::<u64>()
+                    .ok()
+                    .filter(|uses| *uses > 0)
+                    .map(Some)
+                    .ok_or_else(|| format!("Invalid number of uses {uses:?}")),
+    }
+}
+
+#[instrument(skip(state, permissions))]
+async fn preview_keyword(State(state): State<AppState>,
+                         Path(keyword): Path<String>,
+                         permissions: Permissions)
+                         -> AppResult<impl IntoResponse> {
+    render_preview(&state, &permissions, keyword).await
+}
+
+/// render the interstitial page showing where a shortlink goes, without following it
+async fn render_preview(state: &AppState,
+                        permissions: &Permissions,
+                        keyword: String)
+                        -> AppResult<Response> {
     let shortlink = state.get_shortlink(&keyword).await?;
 
-    let path = match shortlink {
-        Some(shortlink) => {
-            // update the state
-            state.hit_shortlink(&keyword).await?;
+    if shortlink.as_ref()
+                .is_some_and(|shortlink| !permissions.can_view(shortlink))
+    {
+        return Ok(permissions.denied(state, &format!("see {keyword}")));
+    }
+
+    Ok(RenderHtml("preview",
+                  state.get_engine(),
+                  json!({
+                      "keyword": keyword,
+                      "shortlink": shortlink.map(|shortlink| permissions.redact(shortlink))
+                  })).into_response())
+}
+
+#[instrument(skip(state, permissions, csrf_token))]
+async fn get_keyword(State(state): State<AppState>,
+                     Path(keyword): Path<String>,
+                     permissions: Permissions,
+                     csrf_token: CsrfToken,
+                     headers: HeaderMap)
+                     -> AppResult<Response> {
+    // `go/keyword+` previews the link instead of following it
+    if let Some(keyword) = keyword.strip_suffix('+') {
+        return render_preview(&state, &permissions, keyword.to_string()).await;
+    }
+
+    let Some(shortlink) = state.get_shortlink(&keyword).await? else {
+        return Ok(Redirect::temporary(&format!("/edit/{keyword}")).into_response());
+    };
+
+    if let Some(refused) = refuse_to_follow(&state, &permissions, &shortlink).await? {
+        return Ok(refused);
+    }
+
+    if shortlink.is_protected() {
+        return Ok(unlock_page(&state,
+                              &shortlink,
+                              &csrf_token,
+                              StatusCode::OK,
+                              None));
+    }
+
+    follow(&state, &shortlink, &headers).await
+}
+
+#[derive(Deserialize)]
+struct UnlockForm {
+    password: String,
+}
+
+/// the passphrase prompt of a protected link posts back here
+#[instrument(skip(state, permissions, csrf_token, headers, form))]
+async fn unlock_keyword(State(state): State<AppState>,
+                        Path(keyword): Path<String>,
+                        permissions: Permissions,
+                        csrf_token: CsrfToken,
+                        headers: HeaderMap,
+                        Form(form): Form<UnlockForm>)
+                        -> AppResult<Response> {
+    let Some(shortlink) = state.get_shortlink(&keyword).await? else {
+        return Ok(no_such_link(&state, &keyword));
+    };
+
+    if let Some(refused) = refuse_to_follow(&state, &permissions, &shortlink).await? {
+        return Ok(refused);
+    }
+
+    if shortlink.is_protected() && !shortlink.check_password(&form.password) {
+        warn!(keyword, "Wrong password for protected link");
+        return Ok(unlock_page(&state,
+                              &shortlink,
+                              &csrf_token,
+                              StatusCode::UNAUTHORIZED,
+                              Some("Wrong password")));
+    }
+
+    let mut response = follow(&state, &shortlink, &headers).await?;
+    // a 307 or 308 would have the browser post the password on to the destination
+    if response.status().is_redirection() {
+        *response.status_mut() = StatusCode::SEE_OTHER;
+    }
+
+    Ok(response)
+}
+
+/// the page to show instead of following a link, if it can't be followed by this visitor
+async fn refuse_to_follow(state: &AppState,
+                          permissions: &Permissions,
+                          shortlink: &Shortlink)
+                          -> AppResult<Option<Response>> {
+    if shortlink.private
+       && permissions.identity().is_none()
+       && state.get_config().private_links_require_auth
+    {
+        return Ok(Some(sign_in_to_follow(state, &shortlink.keyword)));
+    }
+
+    // no point asking for the password of a link that is used up
+    if let Some(max_uses) = shortlink.max_uses {
+        if state.get_uses(&shortlink.keyword).await? >= max_uses {
+            return Ok(Some(used_up(state, shortlink)));
+        }
+    }
+
+    Ok(None)
+}
+
+fn unlock_page(state: &AppState,
+               shortlink: &Shortlink,
+               csrf_token: &CsrfToken,
+               status: StatusCode,
+               error: Option<&str>)
+               -> Response {
+    (status,
+     RenderHtml("unlock",
+                state.get_engine(),
+                json!({
+                    "keyword": shortlink.keyword,
+                    "description": shortlink.description,
+                    "csrf_token": csrf_token,
+                    "error": error
+                })))
+                    .into_response()
+}
+
+fn used_up(state: &AppState, shortlink: &Shortlink) -> Response {
+    error_page(state.get_engine(),
+               StatusCode::GONE,
+               format!("{} could only be used {} times and has been used up",
+                       shortlink.keyword,
+                       shortlink.max_uses.unwrap_or_default()))
+}
+
+/// count the hit and send the visitor on their way
+async fn follow(state: &AppState,
+                shortlink: &Shortlink,
+                headers: &HeaderMap)
+                -> AppResult<Response> {
+    let target = routing::resolve(shortlink, headers, Utc::now());
+
+    // the link may predate a domain being denied
+    if let Some(violation) =
+        Url::parse(&target.url).ok()
+                               .and_then(|url| domain_violation(state.get_config(), &url))
+    {
+        return Ok(error_page(state.get_engine(),
+                             StatusCode::FORBIDDEN,
+                             violation));
+    }
 
-            shortlink.url
+    // claimed only now so a refused visit doesn't use the link up
+    if let Some(max_uses) = shortlink.max_uses {
+        if state.claim_use(&shortlink.keyword).await? > max_uses {
+            return Ok(used_up(state, shortlink));
         }
-        None => format!("/edit/{keyword}"),
+    }
+
+    // update the state
+    state.hit_shortlink(&shortlink.keyword, target.destination)
+         .await?;
+
+    Ok(redirect(shortlink, target))
+}
+
+/// send anonymous visitors of a private link to the login, and back to the link afterwards
+fn sign_in_to_follow(state: &AppState, keyword: &str) -> Response {
+    if state.get_oidc().is_none() {
+        // the SSO proxy should have signed them in already
+        return error_page(state.get_engine(),
+                          StatusCode::UNAUTHORIZED,
+                          format!("Sign in to follow {keyword}"));
+    }
+
+    let next =
+        form_urlencoded::byte_serialize(format!("/{keyword}").as_bytes()).collect::<String>();
+
+    Redirect::to(&format!("/auth/login?next={next}")).into_response()
+}
+
+fn redirect(shortlink: &Shortlink, target: Target) -> Response {
+    let kind = shortlink.redirect_kind;
+    // a cached redirect would pin the client to one destination, or skip the password and use count
+    let uncacheable =
+        routing::varies(shortlink) || shortlink.is_protected() || shortlink.max_uses.is_some();
+    let cache_control = match uncacheable {
+        true => "no-store",
+        false => kind.cache_control(),
     };
 
-    Ok(Redirect::temporary(&path))
+    // links saved before urls were stored the way they are parsed may not fit in a header
+    let location = match target.url.is_ascii() {
+        true => target.url,
+        false => Url::parse(&target.url).map_or(target.url, String::from),
+    };
+
+    let headers = [(LOCATION, location),
+                   (CACHE_CONTROL, cache_control.to_string())];
+    let mut response = (kind.status_code(), headers).into_response();
+
+    if let Some(cookie) = target.set_cookie.and_then(|cookie| cookie.parse().ok()) {
+        response.headers_mut().insert(SET_COOKIE, cookie);
+    }
+
+    response
 }
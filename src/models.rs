@@ -1,9 +1,19 @@
+use argon2::{password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier,
+                             SaltString},
+             Argon2};
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+use utoipa::ToSchema;
 
-#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+use crate::rules::Rule;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, ToSchema)]
 pub struct Shortlink {
     pub keyword: String,
     pub url: String,
+    #[serde(default)]
     pub owner: String,
     #[serde(default)]
     pub hits: usize,
@@ -11,4 +21,178 @@ pub struct Shortlink {
     pub private: bool,
     #[serde(default)]
     pub description: String,
+    #[serde(default)]
+    pub modified: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub redirect_kind: RedirectKind,
+    /// when set, traffic is split between these instead of going to `url`
+    #[serde(default)]
+    pub destinations: Vec<Destination>,
+    /// keep sending a client to the destination it was first given
+    #[serde(default)]
+    pub sticky: bool,
+    /// checked in order before anything else, the first match wins
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    /// other users allowed to change the link, but not to hand it over
+    #[serde(default)]
+    pub co_owners: Vec<String>,
+    /// argon2 hash of the passphrase needed to follow the link, see [`Shortlink::redacted`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = "redacted")]
+    pub password_hash: Option<String>,
+    /// the link stops working after this many uses
+    #[serde(default)]
+    pub max_uses: Option<u64>,
+}
+
+/// stands in for the password hash anywhere outside the database
+pub const REDACTED: &str = "redacted";
+
+/// One of several weighted destinations for a shortlink
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct Destination {
+    pub url: String,
+    pub weight: u32,
+    #[serde(default)]
+    pub hits: usize,
+}
+
+/// destinations are edited as `weight url` lines, the weight defaulting to 1
+impl FromStr for Destination {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let (weight, url) = match line.trim().split_once(char::is_whitespace) {
+            Some((weight, url)) => {
+                let weight =
+                    weight.parse::<u32>()
+                          .map_err(|_| format!("Invalid weight {weight:?} for destination {url}"))?;
+                (weight, url.trim())
+            }
+            None => (1, line.trim()),
+        };
+
+        Ok(Self { url: url.to_string(),
+                  weight,
+                  hits: 0 })
+    }
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.weight, self.url)
+    }
+}
+
+impl Shortlink {
+    pub fn is_owned_by(&self, user: &str) -> bool {
+        self.owner.eq_ignore_ascii_case(user)
+    }
+
+    /// links nobody owns yet can be changed by anyone
+    pub fn is_editable_by(&self, user: &str) -> bool {
+        self.owner.is_empty()
+        || self.is_owned_by(user)
+        || self.co_owners
+               .iter()
+               .any(|co_owner| co_owner.eq_ignore_ascii_case(user))
+    }
+
+    pub fn is_protected(&self) -> bool {
+        self.password_hash.is_some()
+    }
+
+    /// only the hash of the passphrase is kept
+    pub fn set_password(&mut self, passphrase: &str) -> Result<(), String> {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Argon2::default().hash_password(passphrase.as_bytes(), &salt)
+                                    .map_err(|err| format!("Failed to hash the password: {err}"))?;
+
+        self.password_hash = Some(hash.to_string());
+        Ok(())
+    }
+
+    pub fn check_password(&self, passphrase: &str) -> bool {
+        self.password_hash
+            .as_deref()
+            .and_then(|hash| PasswordHash::new(hash).ok())
+            .is_some_and(|hash| {
+                Argon2::default().verify_password(passphrase.as_bytes(), &hash)
+                                 .is_ok()
+            })
+    }
+
+    /// a copy that is safe to hand to templates and api clients, without the password hash
+    pub fn redacted(mut self) -> Self {
+        if self.password_hash.is_some() {
+            self.password_hash = Some(REDACTED.to_string());
+        }
+        self
+    }
+
+    /// a copy without anywhere it goes, for links people have to unlock or use before they may know
+    pub fn without_urls(mut self) -> Self {
+        self.url.clear();
+        for destination in &mut self.destinations {
+            destination.url.clear();
+        }
+        for rule in &mut self.rules {
+            rule.url.clear();
+        }
+        self
+    }
+
+    /// every url this shortlink can send someone to
+    pub fn urls(&self) -> impl Iterator<Item = &str> {
+        let destinations = self.destinations
+                               .iter()
+                               .map(|destination| destination.url.as_str());
+        let rules = self.rules.iter().map(|rule| rule.url.as_str());
+
+        std::iter::once(self.url.as_str()).chain(destinations)
+                                          .chain(rules)
+    }
+}
+
+/// A deleted shortlink, kept until an admin restores or purges it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TrashedShortlink {
+    pub shortlink: Shortlink,
+    pub deleted: DateTime<Utc>,
+    pub deleted_by: Option<String>,
+}
+
+/// The HTTP status used when following a shortlink
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RedirectKind {
+    /// 301, cacheable by clients
+    MovedPermanently,
+    /// 302
+    Found,
+    /// 307
+    #[default]
+    Temporary,
+    /// 308, cacheable by clients
+    Permanent,
+}
+
+impl RedirectKind {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            RedirectKind::MovedPermanently => StatusCode::MOVED_PERMANENTLY,
+            RedirectKind::Found => StatusCode::FOUND,
+            RedirectKind::Temporary => StatusCode::TEMPORARY_REDIRECT,
+            RedirectKind::Permanent => StatusCode::PERMANENT_REDIRECT,
+        }
+    }
+
+    /// permanent redirects may be cached, temporary ones must come back to us so hits are counted
+    pub fn cache_control(&self) -> &'static str {
+        match self {
+            RedirectKind::MovedPermanently | RedirectKind::Permanent => "public, max-age=86400",
+            RedirectKind::Found | RedirectKind::Temporary => "no-store",
+        }
+    }
 }
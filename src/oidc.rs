@@ -0,0 +1,446 @@
+use axum::{extract::{Query, State},
+           http::{header::{HeaderMap, LOCATION, SET_COOKIE},
+                  StatusCode},
+           response::{IntoResponse, Redirect, Response}};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::OnceCell;
+use tracing::{error, info, instrument};
+use url::{Host, Url};
+use uuid::Uuid;
+
+use crate::{auth::Identity,
+            config::OidcConfig,
+            database::{AppState, DatabaseError},
+            error::error_page,
+            routing::get_cookie};
+
+pub const SESSION_COOKIE: &str = "linkshrink_session";
+/// holds the `state` of a login in the browser that started it, so nobody else can finish it
+const LOGIN_COOKIE: &str = "linkshrink_login";
+/// how long someone has to finish logging in at the provider
+const LOGIN_TTL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Error)]
+pub enum OidcError {
+    #[error("Login is not configured")]
+    NotConfigured,
+    #[error("Failed to reach the identity provider: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("The identity provider refused the login: {0}")]
+    Refused(String),
+    #[error("The identity provider sent an invalid id token: {0}")]
+    InvalidToken(String),
+    #[error("The login expired or was already used, please try again")]
+    UnknownLogin,
+    #[error("Refusing to log in through {0}, the identity provider has to be reached over https")]
+    Insecure(String),
+    #[error(transparent)]
+    Database(#[from] DatabaseError),
+}
+
+impl OidcError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            OidcError::NotConfigured => StatusCode::NOT_FOUND,
+            OidcError::Request(_) | OidcError::InvalidToken(_) => StatusCode::BAD_GATEWAY,
+            OidcError::Refused(_) => StatusCode::FORBIDDEN,
+            OidcError::UnknownLogin => StatusCode::BAD_REQUEST,
+            OidcError::Insecure(_) | OidcError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// The parts of the provider's discovery document we use
+#[derive(Debug, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// A login in progress, kept while the user is away at the provider
+#[derive(Debug, Serialize, Deserialize)]
+struct PendingLogin {
+    nonce: String,
+    next: String,
+}
+
+/// OpenID Connect authorization code flow against a single provider
+pub struct Oidc {
+    config: OidcConfig,
+    http: reqwest::Client,
+    metadata: OnceCell<ProviderMetadata>,
+}
+
+impl Oidc {
+    pub fn new(config: OidcConfig) -> Self {
+        Self { config,
+               http: reqwest::Client::new(),
+               metadata: OnceCell::new() }
+    }
+
+    /// sessions only need the `Secure` flag when we are served over https
+    pub fn secure_cookies(&self) -> bool {
+        self.config.redirect_url.starts_with("https://")
+    }
+
+    /// the discovery document, fetched the first time someone logs in
+    async fn metadata(&self) -> Result<&ProviderMetadata, OidcError> {
+        self.metadata
+            .get_or_try_init(|| async {
+                let url = format!("{}/.well-known/openid-configuration", self.config.issuer);
+                require_https(&url)?;
+                info!(url, "Fetching OpenID Connect provider metadata");
+
+                let metadata = self.http
+                                   .get(url)
+                                   .send()
+                                   .await?
+                                   .error_for_status()?
+                                   .json::<ProviderMetadata>()
+                                   .await?;
+                require_https(&metadata.token_endpoint)?;
+                Ok(metadata)
+            })
+            .await
+    }
+
+    async fn authorize_url(&self, state: &str, nonce: &str) -> Result<Url, OidcError> {
+        let metadata = self.metadata().await?;
+        let mut url = Url::parse(&metadata.authorization_endpoint).map_err(|err| {
+                          OidcError::Refused(format!("invalid authorization endpoint: {err}"))
+                      })?;
+
+        url.query_pairs_mut()
+           .append_pair("response_type", "code")
+           .append_pair("client_id", &self.config.client_id)
+           .append_pair("redirect_uri", &self.config.redirect_url)
+           .append_pair("scope", &self.config.scopes)
+           .append_pair("state", state)
+           .append_pair("nonce", nonce);
+
+        Ok(url)
+    }
+
+    /// trade the authorization code for an id token and work out who logged in
+    async fn exchange(&self, code: &str, nonce: &str) -> Result<Identity, OidcError> {
+        let metadata = self.metadata().await?;
+
+        let response = self.http
+                           .post(&metadata.token_endpoint)
+                           .basic_auth(&self.config.client_id, Some(&self.config.client_secret))
+                           .form(&[("grant_type", "authorization_code"),
+                                   ("code", code),
+                                   ("redirect_uri", &self.config.redirect_url),
+                                   ("client_id", &self.config.client_id)])
+                           .send()
+                           .await?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(OidcError::Refused(format!("token endpoint returned {status}: {body}")));
+        }
+
+        let tokens = response.json::<TokenResponse>().await?;
+        let claims = self.validate_id_token(metadata, &tokens.id_token, nonce)?;
+
+        let claim = |name: &str| claims.get(name).and_then(Value::as_str).map(str::to_string);
+        let email = claim("email");
+        let user =
+            claim(&self.config.user_claim).or_else(|| email.clone())
+                                          .or_else(|| claim("sub"))
+                                          .ok_or_else(|| {
+                                              OidcError::InvalidToken("no user claim".to_string())
+                                          })?;
+
+        let groups = claims.get("groups")
+                           .and_then(Value::as_array)
+                           .map(|groups| {
+                               groups.iter()
+                                     .filter_map(Value::as_str)
+                                     .map(str::to_string)
+                                     .collect()
+                           })
+                           .unwrap_or_default();
+
+        Ok(Identity { user,
+                      email,
+                      groups })
+    }
+
+    /// The id token came straight from the token endpoint over an https connection we opened, so as
+    /// the spec allows we rely on that rather than checking its signature, but everything else is
+    /// checked
+    fn validate_id_token(&self,
+                         metadata: &ProviderMetadata,
+                         id_token: &str,
+                         nonce: &str)
+                         -> Result<Value, OidcError> {
+        let invalid = |reason: &str| OidcError::InvalidToken(reason.to_string());
+
+        let payload = id_token.split('.')
+                              .nth(1)
+                              .ok_or_else(|| invalid("not a JWT"))?;
+        let payload = URL_SAFE_NO_PAD.decode(payload.trim_end_matches('='))
+                                     .map_err(|_| invalid("payload is not base64"))?;
+        let claims =
+            serde_json::from_slice::<Value>(&payload).map_err(|_| invalid("payload is not json"))?;
+
+        let issuer = claims.get("iss")
+                           .and_then(Value::as_str)
+                           .unwrap_or_default();
+        if issuer.trim_end_matches('/') != metadata.issuer.trim_end_matches('/') {
+            return Err(invalid("wrong issuer"));
+        }
+
+        let audience_matches = match claims.get("aud") {
+            Some(Value::String(audience)) => *audience == self.config.client_id,
+            Some(Value::Array(audiences)) => {
+                audiences.iter()
+                         .any(|audience| *audience == self.config.client_id.as_str())
+            }
+            _ => false,
+        };
+        if !audience_matches {
+            return Err(invalid("wrong audience"));
+        }
+
+        let expires = claims.get("exp")
+                            .and_then(Value::as_i64)
+                            .unwrap_or_default();
+        if expires <= Utc::now().timestamp() {
+            return Err(invalid("expired"));
+        }
+
+        if claims.get("nonce").and_then(Value::as_str) != Some(nonce) {
+            return Err(invalid("wrong nonce"));
+        }
+
+        Ok(claims)
+    }
+}
+
+/// id tokens are trusted for having come from the token endpoint, so that has to be https, but
+/// for a provider on this machine or a container next to it, like the mock one in docker-compose
+fn require_https(url: &str) -> Result<(), OidcError> {
+    let insecure = || OidcError::Insecure(url.to_string());
+    let parsed = Url::parse(url).map_err(|_| insecure())?;
+
+    let local = match parsed.host() {
+        Some(Host::Domain(domain)) => domain == "localhost" || !domain.contains('.'),
+        Some(Host::Ipv4(address)) => address.is_loopback(),
+        Some(Host::Ipv6(address)) => address.is_loopback(),
+        None => false,
+    };
+
+    match parsed.scheme() == "https" || local {
+        true => Ok(()),
+        false => Err(insecure()),
+    }
+}
+
+fn session_key(id: &str) -> String {
+    format!("session::{id}")
+}
+
+fn login_key(state: &str) -> String {
+    format!("oidc::{state}")
+}
+
+/// the identity behind the session cookie, if it hasn't expired
+pub async fn session_identity(state: &AppState, headers: &HeaderMap) -> Option<Identity> {
+    let id = get_cookie(headers, SESSION_COOKIE)?;
+
+    state.get_json::<Identity>(&session_key(id))
+         .await
+         .map_err(|err| error!("Failed to look up session: {err}"))
+         .ok()
+         .flatten()
+}
+
+/// only ever send people back to a path on this site, browsers drop whitespace and control
+/// characters from `/\t/evil.com` and follow what is left
+fn local_path(next: Option<String>) -> String {
+    next.filter(|next| {
+            next.starts_with('/')
+            && !next.starts_with("//")
+            && !next.contains('\\')
+            && !next.contains(|c: char| c.is_whitespace() || c.is_control())
+        })
+        .unwrap_or_else(|| "/links".to_string())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginQuery {
+    next: Option<String>,
+}
+
+/// send the user off to the identity provider
+#[instrument(skip(state))]
+pub async fn login(State(state): State<AppState>, Query(query): Query<LoginQuery>) -> Response {
+    let result = async {
+        let oidc = state.get_oidc().ok_or(OidcError::NotConfigured)?;
+
+        let login_state = Uuid::new_v4().to_string();
+        let pending = PendingLogin { nonce: Uuid::new_v4().to_string(),
+                                     next: local_path(query.next) };
+        let url = oidc.authorize_url(&login_state, &pending.nonce).await?;
+
+        state.set_expiring(&login_key(&login_state), &pending, LOGIN_TTL)
+             .await?;
+
+        let cookie = format!("{LOGIN_COOKIE}={login_state}; Path=/auth; Max-Age={}; HttpOnly; SameSite=Lax{}",
+                             LOGIN_TTL.as_secs(),
+                             secure_attribute(oidc));
+
+        Ok::<_, OidcError>(([(SET_COOKIE, cookie)], Redirect::to(url.as_str())))
+    };
+
+    match result.await {
+        Ok(response) => response.into_response(),
+        Err(err) => {
+            error!("Failed to start login: {err}");
+            error_page(state.get_engine(), err.status_code(), err.to_string())
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+/// the identity provider sends the user back here with an authorization code
+#[instrument(skip(state, headers, query))]
+pub async fn callback(State(state): State<AppState>,
+                      headers: HeaderMap,
+                      Query(query): Query<CallbackQuery>)
+                      -> Response {
+    let result = async {
+        let oidc = state.get_oidc().ok_or(OidcError::NotConfigured)?;
+
+        if let Some(error) = query.error {
+            return Err(OidcError::Refused(query.error_description.unwrap_or(error)));
+        }
+
+        let (Some(code), Some(login_state)) = (query.code, query.state) else {
+            return Err(OidcError::UnknownLogin);
+        };
+
+        // a login started in another browser, e.g. a link someone was sent to sign them in as
+        // somebody else
+        if get_cookie(&headers, LOGIN_COOKIE) != Some(login_state.as_str()) {
+            return Err(OidcError::UnknownLogin);
+        }
+
+        // each login can only be completed once
+        let key = login_key(&login_state);
+        let pending = state.get_json::<PendingLogin>(&key)
+                           .await?
+                           .ok_or(OidcError::UnknownLogin)?;
+        state.delete_key(&key).await?;
+
+        let identity = oidc.exchange(&code, &pending.nonce).await?;
+        info!(user = identity.user, "User logged in");
+
+        let session = Uuid::new_v4().to_string();
+        let ttl = state.get_config().session_ttl;
+        state.set_expiring(&session_key(&session), &identity, ttl)
+             .await?;
+
+        let cookie =
+            format!("{SESSION_COOKIE}={session}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax{}",
+                    ttl.as_secs(),
+                    secure_attribute(oidc));
+        let done = format!("{LOGIN_COOKIE}=; Path=/auth; Max-Age=0; HttpOnly; SameSite=Lax");
+
+        Ok((StatusCode::SEE_OTHER,
+            [(LOCATION, pending.next),
+             (SET_COOKIE, cookie),
+             (SET_COOKIE, done)]))
+    };
+
+    match result.await {
+        Ok(response) => response.into_response(),
+        Err(err) => {
+            error!("Failed to complete login: {err}");
+            error_page(state.get_engine(), err.status_code(), err.to_string())
+        }
+    }
+}
+
+/// only mark cookies secure when the site is served over https, or they never come back
+fn secure_attribute(oidc: &Oidc) -> &'static str {
+    if oidc.secure_cookies() {
+        "; Secure"
+    } else {
+        ""
+    }
+}
+
+/// forget the session, both here and in the browser
+#[instrument(skip(state, headers))]
+pub async fn logout(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Some(session) = get_cookie(&headers, SESSION_COOKIE) {
+        if let Err(err) = state.delete_key(&session_key(session)).await {
+            error!("Failed to delete session: {err}");
+        }
+    }
+
+    let cookie = format!("{SESSION_COOKIE}=; Path=/; Max-Age=0; HttpOnly; SameSite=Lax");
+
+    (StatusCode::SEE_OTHER, [(LOCATION, "/links".to_string()), (SET_COOKIE, cookie)]).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn logins_only_return_to_this_site() {
+        for next in ["/links", "/edit/docs?x=1"] {
+            assert_eq!(local_path(Some(next.to_string())), next);
+        }
+        for next in ["https://evil.com",
+                     "//evil.com",
+                     "/\\evil.com",
+                     "/\t/evil.com",
+                     "/ /evil.com"]
+        {
+            assert_eq!(local_path(Some(next.to_string())), "/links", "{next:?}");
+        }
+        assert_eq!(local_path(None), "/links");
+    }
+
+    #[test]
+    fn providers_have_to_use_https_unless_local() {
+        for url in ["https://login.example.com/token",
+                    "http://localhost:8081/token",
+                    "http://127.0.0.1/token",
+                    "http://[::1]/token",
+                    "http://mock-oidc:8081/default/token"]
+        {
+            assert!(require_https(url).is_ok(), "{url}");
+        }
+        for url in ["http://login.example.com/token",
+                    "http://10.0.0.1/token",
+                    "token"]
+        {
+            assert!(require_https(url).is_err(), "{url}");
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use axum::{extract::State,
+           response::{IntoResponse, Response},
+           Json};
+use axum_template::RenderHtml;
+use serde_json::json;
+use tracing::instrument;
+use utoipa::{openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
+             Modify, OpenApi};
+
+use crate::{api::{self, ErrorBody, ErrorDetail, LinkInput, LinkPage},
+            csrf::CsrfToken,
+            database::AppState,
+            import::{self, ConflictPolicy, ImportFormat, ImportReport, ImportRow, Outcome},
+            models::{Destination, RedirectKind, Shortlink},
+            rules::{Condition, Rule}};
+
+/// The OpenAPI document, generated from the handlers and the types they take and return
+#[derive(OpenApi)]
+#[openapi(paths(api::list_links,
+                api::create_link,
+                api::get_link,
+                api::replace_link,
+                api::update_link,
+                api::delete_link,
+                import::import_links,
+                crate::update_keyword),
+          components(schemas(Shortlink,
+                             Destination,
+                             RedirectKind,
+                             Rule,
+                             Condition,
+                             LinkInput,
+                             LinkPage,
+                             ErrorBody,
+                             ErrorDetail,
+                             ImportReport,
+                             ImportRow,
+                             Outcome,
+                             ConflictPolicy,
+                             ImportFormat,
+                             crate::UpdateForm)),
+          modifiers(&ApiTokens),
+          security(("api_token" = [])),
+          tags((name = "links", description = "Shortlinks as JSON"),
+               (name = "pages", description = "Forms the web pages post")))]
+struct ApiDoc;
+
+/// personal API tokens from `/settings/tokens`, sent as bearer tokens
+struct ApiTokens;
+
+impl Modify for ApiTokens {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme("api_token",
+                                           SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer)
+                                                                                  .description(Some("A personal API token from /settings/tokens"))
+                                                                                  .build()));
+        }
+    }
+}
+
+/// the OpenAPI document, for generating clients
+#[instrument]
+pub async fn spec() -> impl IntoResponse {
+    Json(ApiDoc::openapi())
+}
+
+/// a page to read the OpenAPI document and try requests from the browser
+#[instrument(skip(state, csrf_token))]
+pub async fn docs(State(state): State<AppState>, csrf_token: CsrfToken) -> Response {
+    RenderHtml("api_docs",
+               state.get_engine(),
+               json!({
+                   "csrf_token": csrf_token
+               })).into_response()
+}
@@ -0,0 +1,271 @@
+use axum::{async_trait,
+           extract::FromRequestParts,
+           http::{request::Parts, StatusCode},
+           response::Response};
+use std::convert::Infallible;
+
+use crate::{auth::{CurrentUser, Identity},
+            config::Config,
+            database::AppState,
+            error::error_page,
+            models::Shortlink,
+            tokens::TokenScope};
+
+/// What the user behind a request is allowed to do
+///
+/// Without any authentication configured nobody can be told apart, so everyone may change any link
+/// the way linkshrink always worked, but only gets the admin pages when they are opened up
+#[derive(Debug, Clone)]
+pub struct Permissions {
+    identity: Option<Identity>,
+    admin: bool,
+    /// no authentication is configured
+    open: bool,
+    /// set when the request came with an API token, which may only do what its scope allows
+    scope: Option<TokenScope>,
+}
+
+impl Permissions {
+    pub fn new(config: &Config, user: CurrentUser, scope: Option<TokenScope>) -> Self {
+        let admin = match user.identity() {
+            _ if !config.auth_enabled() => config.anonymous_admin,
+            Some(identity) => {
+                config.is_admin_user(&identity.user)
+                || identity.groups
+                           .iter()
+                           .any(|group| config.admin_groups.contains(&group.to_lowercase()))
+            }
+            None => false,
+        };
+
+        Self { identity: user.0,
+               admin: admin && scope.is_none_or(|scope| scope.allows(TokenScope::Admin)),
+               open: !config.auth_enabled(),
+               scope }
+    }
+
+    pub fn identity(&self) -> Option<&Identity> {
+        self.identity.as_ref()
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.admin
+    }
+
+    /// whether this request came with an API token rather than a browser session
+    pub fn is_token(&self) -> bool {
+        self.scope.is_some()
+    }
+
+    /// read only tokens can't change anything
+    fn can_write(&self) -> bool {
+        self.scope
+            .is_none_or(|scope| scope.allows(TokenScope::Write))
+    }
+
+    /// add a new link, which with auth takes someone to own it
+    pub fn can_create(&self) -> bool {
+        self.can_write() && (self.open || self.identity.is_some())
+    }
+
+    /// change where a link goes and what it says about itself
+    pub fn can_edit(&self, shortlink: &Shortlink) -> bool {
+        self.can_write() && self.can_edit_as_owner(shortlink)
+    }
+
+    fn can_edit_as_owner(&self, shortlink: &Shortlink) -> bool {
+        self.admin
+        || self.open
+        || self.identity
+               .as_ref()
+               .is_some_and(|identity| shortlink.is_editable_by(&identity.user))
+    }
+
+    /// hand a link over, pick its co-owners, or delete it, links nobody owns yet by anyone signed in
+    pub fn can_manage(&self, shortlink: &Shortlink) -> bool {
+        self.can_write() && self.can_manage_as_owner(shortlink)
+    }
+
+    fn can_manage_as_owner(&self, shortlink: &Shortlink) -> bool {
+        self.admin
+        || self.open
+        || self.identity
+               .as_ref()
+               .is_some_and(|identity| {
+                   shortlink.owner.is_empty() || shortlink.is_owned_by(&identity.user)
+               })
+    }
+
+    /// see a private link's details, or that it exists at all
+    pub fn can_view(&self, shortlink: &Shortlink) -> bool {
+        !shortlink.private || self.is_known_editor(shortlink)
+    }
+
+    /// someone we can tell apart who may change the link, without auth everyone may change
+    /// anything but nobody is known to own a private link
+    fn is_known_editor(&self, shortlink: &Shortlink) -> bool {
+        self.identity.is_some() && self.can_edit_as_owner(shortlink)
+    }
+
+    /// a link as this user may see it, never with its password hash, and when it's behind a
+    /// passphrase or a use limit only with its urls if they could change it anyway
+    pub fn redact(&self, shortlink: Shortlink) -> Shortlink {
+        let hide_urls = (shortlink.is_protected() || shortlink.max_uses.is_some())
+                        && !self.can_edit_as_owner(&shortlink);
+        let shortlink = shortlink.redacted();
+
+        match hide_urls {
+            true => shortlink.without_urls(),
+            false => shortlink,
+        }
+    }
+
+    /// the page to show when a check above fails
+    pub fn denied(&self, state: &AppState, action: &str) -> Response {
+        let message = match &self.identity {
+            Some(identity) => format!("{} isn't allowed to {action}", identity.user),
+            None => format!("Sign in to {action}"),
+        };
+
+        error_page(state.get_engine(), StatusCode::FORBIDDEN, message)
+    }
+
+    /// for pages only admins may see, the page to show everyone else
+    pub fn require_admin(&self, state: &AppState) -> Option<Response> {
+        (!self.admin).then(|| self.denied(state, "use the admin pages"))
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for Permissions {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts,
+                                state: &AppState)
+                                -> Result<Self, Self::Rejection> {
+        let user = CurrentUser::from_request_parts(parts, state).await?;
+        let scope = parts.extensions.get::<TokenScope>().copied();
+
+        Ok(Self::new(state.get_config(), user, scope))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(auth: bool) -> Config {
+        Config { auth_user_header: auth.then(|| "X-User".to_string()),
+                 auth_email_header: None,
+                 oidc: None,
+                 admin_users: vec!["root".to_string()],
+                 admin_groups: vec!["wheel".to_string()],
+                 anonymous_admin: false,
+                 ..Config::from_env() }
+    }
+
+    fn user(name: &str, groups: &[&str]) -> CurrentUser {
+        CurrentUser(Some(Identity { user: name.to_string(),
+                                    email: None,
+                                    groups: groups.iter()
+                                                  .map(|group| group.to_string())
+                                                  .collect() }))
+    }
+
+    fn link(owner: &str, private: bool) -> Shortlink {
+        Shortlink { keyword: "docs".to_string(),
+                    url: "https://example.com".to_string(),
+                    owner: owner.to_string(),
+                    co_owners: vec!["carol".to_string()],
+                    private,
+                    ..Default::default() }
+    }
+
+    #[test]
+    fn without_auth_everyone_edits_but_nobody_sees_private_links() {
+        let anyone = Permissions::new(&config(false), CurrentUser(None), None);
+
+        assert!(anyone.can_create());
+        assert!(anyone.can_edit(&link("alice", false)));
+        assert!(anyone.can_manage(&link("alice", false)));
+        assert!(anyone.can_view(&link("alice", false)));
+        assert!(!anyone.can_view(&link("alice", true)));
+        assert!(!anyone.is_admin());
+    }
+
+    #[test]
+    fn without_auth_admin_pages_are_opt_in() {
+        let config = Config { anonymous_admin: true,
+                              ..config(false) };
+
+        assert!(Permissions::new(&config, CurrentUser(None), None).is_admin());
+    }
+
+    #[test]
+    fn anonymous_users_change_nothing_with_auth() {
+        let anonymous = Permissions::new(&config(true), CurrentUser(None), None);
+
+        assert!(!anonymous.can_create());
+        for owner in ["alice", ""] {
+            assert!(!anonymous.can_edit(&link(owner, false)));
+            assert!(!anonymous.can_manage(&link(owner, false)));
+        }
+        assert!(anonymous.can_view(&link("alice", false)));
+        assert!(!anonymous.can_view(&link("alice", true)));
+    }
+
+    #[test]
+    fn owners_and_co_owners() {
+        let config = config(true);
+        let alice = Permissions::new(&config, user("Alice", &[]), None);
+        let carol = Permissions::new(&config, user("carol", &[]), None);
+        let dave = Permissions::new(&config, user("dave", &[]), None);
+
+        assert!(alice.can_edit(&link("alice", true)));
+        assert!(alice.can_manage(&link("alice", true)));
+        assert!(alice.can_view(&link("alice", true)));
+
+        assert!(carol.can_edit(&link("alice", true)));
+        assert!(!carol.can_manage(&link("alice", true)));
+        assert!(carol.can_view(&link("alice", true)));
+
+        assert!(!dave.can_edit(&link("alice", false)));
+        assert!(!dave.can_manage(&link("alice", false)));
+        assert!(!dave.can_view(&link("alice", true)));
+        assert!(dave.can_edit(&link("", false)));
+        assert!(dave.can_manage(&link("", false)));
+    }
+
+    #[test]
+    fn admins_by_name_or_group() {
+        let config = config(true);
+
+        for admin in [user("ROOT", &[]), user("eve", &["Wheel"])] {
+            let admin = Permissions::new(&config, admin, None);
+            assert!(admin.is_admin());
+            assert!(admin.can_manage(&link("alice", true)));
+            assert!(admin.can_view(&link("alice", true)));
+        }
+    }
+
+    #[test]
+    fn tokens_only_do_what_their_scope_allows() {
+        let config = config(true);
+        let scoped = |name, scope| Permissions::new(&config, user(name, &[]), Some(scope));
+
+        let read = scoped("alice", TokenScope::Read);
+        assert!(!read.can_create());
+        assert!(!read.can_edit(&link("alice", false)));
+        assert!(!read.can_manage(&link("alice", false)));
+        assert!(read.can_view(&link("alice", true)));
+
+        let write = scoped("alice", TokenScope::Write);
+        assert!(write.can_create());
+        assert!(write.can_manage(&link("alice", false)));
+        assert!(!write.can_edit(&link("bob", false)));
+
+        assert!(!scoped("root", TokenScope::Write).is_admin());
+        assert!(scoped("root", TokenScope::Admin).is_admin());
+        assert!(!scoped("alice", TokenScope::Admin).is_admin());
+    }
+}
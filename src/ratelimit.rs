@@ -0,0 +1,69 @@
+use axum::{extract::{ConnectInfo, State},
+           http::{header::RETRY_AFTER, Method, Request, StatusCode},
+           middleware::Next,
+           response::Response};
+use chrono::Utc;
+use std::net::SocketAddr;
+use tracing::{error, warn};
+
+use crate::{auth::{client_ip, CurrentUser},
+            database::AppState,
+            error::error_page};
+
+/// these are polled by machines and never change anything
+const EXEMPT_PATHS: [&str; 2] = ["/metrics", "/favicon.ico"];
+
+/// Fixed window rate limits kept in redis, so they hold across replicas
+///
+/// Signed in users are counted by name wherever they come from, everyone else by address, with
+/// separate budgets for reading and for changing things
+pub async fn limit<B>(State(state): State<AppState>,
+                      ConnectInfo(peer): ConnectInfo<SocketAddr>,
+                      request: Request<B>,
+                      next: Next<B>)
+                      -> Response {
+    if EXEMPT_PATHS.contains(&request.uri().path()) {
+        return next.run(request).await;
+    }
+
+    let config = state.get_config();
+    let (kind, budget) = match *request.method() {
+        Method::GET | Method::HEAD | Method::OPTIONS => ("read", config.rate_limit_reads),
+        _ => ("write", config.rate_limit_writes),
+    };
+    if budget == 0 {
+        return next.run(request).await;
+    }
+
+    let subject = match request.extensions()
+                               .get::<CurrentUser>()
+                               .and_then(CurrentUser::identity)
+    {
+        Some(identity) => format!("user::{}", identity.user.to_lowercase()),
+        None => format!("ip::{}", client_ip(config, peer, request.headers())),
+    };
+
+    let window = config.rate_limit_window.as_secs().max(1);
+    let now = Utc::now().timestamp() as u64;
+    let key = format!("ratelimit::{kind}::{subject}::{}", now / window);
+
+    match state.count_request(&key, config.rate_limit_window).await {
+        Ok(count) if count > budget => {
+            let retry_after = window - now % window;
+            warn!(subject, kind, count, "Rate limited");
+
+            let mut response = error_page(state.get_engine(),
+                                          StatusCode::TOO_MANY_REQUESTS,
+                                          format!("Slow down, try again in {retry_after} seconds"));
+            response.headers_mut()
+                    .insert(RETRY_AFTER, retry_after.into());
+            response
+        }
+        Ok(_) => next.run(request).await,
+        // better to let everyone through than lock everyone out
+        Err(err) => {
+            error!("Failed to count request against rate limit: {err}");
+            next.run(request).await
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use axum::http::header::{HeaderMap, COOKIE};
+use chrono::{DateTime, Utc};
+use rand::{distributions::WeightedIndex, prelude::Distribution};
+
+use crate::{models::Shortlink, rules};
+
+/// cookie remembering which weighted destination a client was sent to, scoped to the keyword path
+pub const DESTINATION_COOKIE: &str = "linkshrink_destination";
+const DESTINATION_COOKIE_MAX_AGE: u64 = 60 * 60 * 24 * 30;
+
+/// Where a request for a shortlink should be sent
+#[derive(Debug)]
+pub struct Target {
+    pub url: String,
+    /// index into `Shortlink::destinations` when one was picked
+    pub destination: Option<usize>,
+    /// cookie to hand back so sticky links keep the same destination
+    pub set_cookie: Option<String>,
+}
+
+/// work out the destination for a request to `shortlink`: routing rules first, then weighted
+/// destinations, then the plain url
+pub fn resolve(shortlink: &Shortlink, headers: &HeaderMap, now: DateTime<Utc>) -> Target {
+    if let Some(rule) = rules::first_match(&shortlink.rules, headers, now) {
+        return Target { url: rule.url.clone(),
+                        destination: None,
+                        set_cookie: None };
+    }
+
+    match pick_destination(shortlink, headers) {
+        Some(index) => {
+            let set_cookie = shortlink.sticky
+                                      .then(|| destination_cookie(&shortlink.keyword, index));
+
+            Target { url: shortlink.destinations[index].url.clone(),
+                     destination: Some(index),
+                     set_cookie }
+        }
+        None => Target { url: shortlink.url.clone(),
+                         destination: None,
+                         set_cookie: None },
+    }
+}
+
+/// whether the same request can be sent to different places, so must not be cached
+pub fn varies(shortlink: &Shortlink) -> bool {
+    !shortlink.destinations.is_empty() || !shortlink.rules.is_empty()
+}
+
+fn destination_cookie(keyword: &str, index: usize) -> String {
+    format!("{DESTINATION_COOKIE}={index}; Path=/{keyword}; Max-Age={DESTINATION_COOKIE_MAX_AGE}; \
+             HttpOnly; SameSite=Lax")
+}
+
+fn pick_destination(shortlink: &Shortlink, headers: &HeaderMap) -> Option<usize> {
+    if shortlink.destinations.is_empty() {
+        return None;
+    }
+
+    if shortlink.sticky {
+        let remembered =
+            get_cookie(headers, DESTINATION_COOKIE).and_then(|value| value.parse::<usize>().ok())
+                                                   .filter(|index| {
+                                                       shortlink.destinations
+                                                                .get(*index)
+                                                                .is_some_and(|d| d.weight > 0)
+                                                   });
+        if remembered.is_some() {
+            return remembered;
+        }
+    }
+
+    // fails when every weight is zero, in which case we fall back to the plain url
+    let weights = WeightedIndex::new(shortlink.destinations.iter().map(|d| d.weight)).ok()?;
+
+    Some(weights.sample(&mut rand::thread_rng()))
+}
+
+/// read a single cookie from the request headers
+pub fn get_cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get_all(COOKIE)
+           .iter()
+           .filter_map(|value| value.to_str().ok())
+           .flat_map(|value| value.split(';'))
+           .filter_map(|pair| pair.trim().split_once('='))
+           .find(|(key, _)| *key == name)
+           .map(|(_, value)| value)
+}
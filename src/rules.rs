@@ -0,0 +1,459 @@
+use axum::http::header::{HeaderMap, HeaderName, ACCEPT_LANGUAGE, USER_AGENT};
+use chrono::{DateTime, Datelike, FixedOffset, NaiveTime, Utc, Weekday};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+use utoipa::ToSchema;
+
+/// Sends matching requests somewhere other than the shortlink's default url
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct Rule {
+    pub condition: Condition,
+    pub url: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Condition {
+    /// between `start` and `end` local time, wrapping past midnight when `end` is before `start`
+    TimeWindow {
+        start: NaiveTime,
+        end: NaiveTime,
+        /// empty means every day
+        #[serde(default)]
+        #[schema(value_type = Vec<String>, example = json!(["Mon", "Tue"]))]
+        days: Vec<Weekday>,
+        #[serde(default)]
+        utc_offset_minutes: i32,
+    },
+    /// case-insensitive regex against the `User-Agent` header
+    UserAgent {
+        #[schema(value_type = String)]
+        pattern: Pattern,
+    },
+    /// language tag accepted by the client, `fr` also matching `fr-CH`
+    AcceptLanguage { language: String },
+    /// case-insensitive regex against an arbitrary header
+    Header {
+        name: String,
+        #[schema(value_type = String)]
+        pattern: Pattern,
+    },
+}
+
+/// A case-insensitive regex, compiled once when its rule is read rather than on every redirect
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub struct Pattern {
+    source: String,
+    /// one that doesn't compile matches nothing
+    regex: Result<Regex, regex::Error>,
+}
+
+impl Pattern {
+    pub fn new(source: impl Into<String>) -> Self {
+        let source = source.into();
+        let regex = RegexBuilder::new(&source).case_insensitive(true).build();
+
+        Self { source, regex }
+    }
+
+    fn is_match(&self, value: &str) -> bool {
+        self.regex.as_ref().is_ok_and(|regex| regex.is_match(value))
+    }
+}
+
+impl From<String> for Pattern {
+    fn from(source: String) -> Self {
+        Self::new(source)
+    }
+}
+
+impl From<Pattern> for String {
+    fn from(pattern: Pattern) -> Self {
+        pattern.source
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl Eq for Pattern {}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+/// the first rule matching the request, if any
+pub fn first_match<'a>(rules: &'a [Rule],
+                       headers: &HeaderMap,
+                       now: DateTime<Utc>)
+                       -> Option<&'a Rule> {
+    rules.iter()
+         .find(|rule| rule.condition.matches(headers, now))
+}
+
+impl Condition {
+    /// check a condition could ever match, rules sent as JSON don't go through the parser
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Condition::TimeWindow { utc_offset_minutes, .. } => {
+                if utc_offset_minutes.abs() >= 24 * 60 {
+                    return Err(format!("Invalid utc offset of {utc_offset_minutes} minutes"));
+                }
+            }
+            Condition::UserAgent { pattern } => {
+                if let Err(err) = &pattern.regex {
+                    return Err(format!("Invalid user agent pattern {:?}: {err}", pattern.source));
+                }
+            }
+            Condition::AcceptLanguage { language } => {
+                if language.trim().is_empty() {
+                    return Err("A language is required".to_string());
+                }
+            }
+            Condition::Header { name, pattern } => {
+                HeaderName::from_str(name).map_err(|_| format!("Invalid header name {name:?}"))?;
+                if let Err(err) = &pattern.regex {
+                    return Err(format!("Invalid header pattern {:?}: {err}", pattern.source));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn matches(&self, headers: &HeaderMap, now: DateTime<Utc>) -> bool {
+        match self {
+            Condition::TimeWindow { start,
+                                    end,
+                                    days,
+                                    utc_offset_minutes, } => {
+                let Some(offset) = FixedOffset::east_opt(utc_offset_minutes * 60) else {
+                    return false;
+                };
+                let local = now.with_timezone(&offset);
+                let time = local.time();
+
+                let in_window = match start <= end {
+                    true => *start <= time && time < *end,
+                    false => *start <= time || time < *end,
+                };
+
+                in_window && (days.is_empty() || days.contains(&local.weekday()))
+            }
+            Condition::UserAgent { pattern } => header_matches(headers, &USER_AGENT, pattern),
+            Condition::AcceptLanguage { language } => {
+                accepted_languages(headers).any(|accepted| language_matches(accepted, language))
+            }
+            Condition::Header { name, pattern } => match HeaderName::from_str(name) {
+                Ok(name) => header_matches(headers, &name, pattern),
+                Err(_) => false,
+            },
+        }
+    }
+}
+
+fn header_matches(headers: &HeaderMap, name: &HeaderName, pattern: &Pattern) -> bool {
+    headers.get_all(name)
+           .iter()
+           .filter_map(|value| value.to_str().ok())
+           .any(|value| pattern.is_match(value))
+}
+
+/// language ranges from `Accept-Language`, skipping any the client refused with `q=0`
+fn accepted_languages(headers: &HeaderMap) -> impl Iterator<Item = &str> {
+    headers.get_all(ACCEPT_LANGUAGE)
+           .iter()
+           .filter_map(|value| value.to_str().ok())
+           .flat_map(|value| value.split(','))
+           .filter_map(|range| {
+               let mut parts = range.split(';').map(str::trim);
+               let language = parts.next().filter(|language| !language.is_empty())?;
+               let refused = parts.filter_map(|param| param.strip_prefix("q="))
+                                  .any(|q| q.parse::<f32>().is_ok_and(|q| q <= 0.0));
+
+               (!refused).then_some(language)
+           })
+}
+
+fn language_matches(accepted: &str, language: &str) -> bool {
+    accepted.len() >= language.len()
+    && accepted[..language.len()].eq_ignore_ascii_case(language)
+    && matches!(accepted.as_bytes().get(language.len()), None | Some(b'-'))
+}
+
+/// rules are edited one per line as `condition => url`, e.g.
+///
+/// ```text
+/// time 09:00-17:00 mon-fri +02:00 => https://dashboard
+/// ua iphone|android => https://apps.apple.com/...
+/// lang fr => https://wiki/fr
+/// header X-Team: sre => https://runbook
+/// ```
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let (condition, url) = line.rsplit_once("=>")
+                                   .ok_or_else(|| format!("Rule {line:?} is missing `=> url`"))?;
+        let url = url.trim();
+        if url.is_empty() {
+            return Err(format!("Rule {line:?} is missing a url"));
+        }
+
+        Ok(Self { condition: condition.parse()?,
+                  url: url.to_string() })
+    }
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(condition: &str) -> Result<Self, Self::Err> {
+        let condition = condition.trim();
+        let (kind, rest) =
+            condition.split_once(char::is_whitespace)
+                     .map(|(kind, rest)| (kind, rest.trim()))
+                     .ok_or_else(|| format!("Incomplete rule condition {condition:?}"))?;
+
+        let condition = match kind.to_ascii_lowercase().as_str() {
+            "time" => parse_time_window(rest),
+            "ua" => Ok(Condition::UserAgent { pattern: Pattern::new(rest) }),
+            "lang" => Ok(Condition::AcceptLanguage { language: rest.to_string() }),
+            "header" => {
+                let (name, pattern) =
+                    rest.split_once(':')
+                        .ok_or_else(|| format!("Header rule {rest:?} should be `Name: pattern`"))?;
+
+                Ok(Condition::Header { name: name.trim().to_string(),
+                                       pattern: Pattern::new(pattern.trim()) })
+            }
+            other => {
+                Err(format!("Unknown rule condition {other:?}, expected time, ua, lang or header"))
+            }
+        }?;
+
+        condition.validate()?;
+        Ok(condition)
+    }
+}
+
+/// `HH:MM-HH:MM [days] [±HH:MM]`
+fn parse_time_window(input: &str) -> Result<Condition, String> {
+    let mut parts = input.split_whitespace();
+
+    let (start, end) =
+        parts.next()
+             .and_then(|window| window.split_once('-'))
+             .ok_or_else(|| format!("Time window {input:?} should start with HH:MM-HH:MM"))?;
+    let start = parse_time(start)?;
+    let end = parse_time(end)?;
+
+    let mut days = vec![];
+    let mut utc_offset_minutes = 0;
+    for part in parts {
+        if part.starts_with(['+', '-']) {
+            utc_offset_minutes = parse_offset(part)?;
+        } else {
+            days.extend(parse_days(part)?);
+        }
+    }
+
+    Ok(Condition::TimeWindow { start,
+                               end,
+                               days,
+                               utc_offset_minutes })
+}
+
+fn parse_time(time: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(time, "%H:%M").map_err(|_| {
+                                                format!("Invalid time {time:?}, expected HH:MM")
+                                            })
+}
+
+fn parse_offset(offset: &str) -> Result<i32, String> {
+    let invalid = || format!("Invalid utc offset {offset:?}, expected ±HH:MM");
+
+    let sign = if offset.starts_with('-') { -1 } else { 1 };
+    let (hours, minutes) = offset[1..].split_once(':').ok_or_else(invalid)?;
+    let hours = hours.parse::<i32>().map_err(|_| invalid())?;
+    let minutes = minutes.parse::<i32>().map_err(|_| invalid())?;
+
+    if hours > 23 || minutes > 59 {
+        return Err(invalid());
+    }
+
+    Ok(sign * (hours * 60 + minutes))
+}
+
+/// `mon-fri`, `sat,sun` or a mix of both
+fn parse_days(input: &str) -> Result<Vec<Weekday>, String> {
+    let parse_day = |day: &str| Weekday::from_str(day).map_err(|_| format!("Invalid day {day:?}"));
+
+    let mut days = vec![];
+    for range in input.split(',').filter(|range| !range.is_empty()) {
+        match range.split_once('-') {
+            Some((from, to)) => {
+                let (mut day, to) = (parse_day(from)?, parse_day(to)?);
+                days.push(day);
+                while day != to {
+                    day = day.succ();
+                    days.push(day);
+                }
+            }
+            None => days.push(parse_day(range)?),
+        }
+    }
+
+    Ok(days)
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} => {}", self.condition, self.url)
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Condition::TimeWindow { start,
+                                    end,
+                                    days,
+                                    utc_offset_minutes, } => {
+                write!(f, "time {}-{}", start.format("%H:%M"), end.format("%H:%M"))?;
+                if !days.is_empty() {
+                    let days = days.iter()
+                                   .map(|day| day.to_string().to_ascii_lowercase())
+                                   .collect::<Vec<_>>();
+                    write!(f, " {}", days.join(","))?;
+                }
+                if *utc_offset_minutes != 0 {
+                    let sign = if *utc_offset_minutes < 0 { '-' } else { '+' };
+                    let minutes = utc_offset_minutes.abs();
+                    write!(f, " {sign}{:02}:{:02}", minutes / 60, minutes % 60)?;
+                }
+                Ok(())
+            }
+            Condition::UserAgent { pattern } => write!(f, "ua {pattern}"),
+            Condition::AcceptLanguage { language } => write!(f, "lang {language}"),
+            Condition::Header { name, pattern } => write!(f, "header {name}: {pattern}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+    use chrono::TimeZone;
+
+    fn at(hour: u32, minute: u32) -> DateTime<Utc> {
+        // a Wednesday
+        Utc.with_ymd_and_hms(2024, 5, 15, hour, minute, 0).unwrap()
+    }
+
+    fn languages(accept: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT_LANGUAGE, HeaderValue::from_static(accept));
+        headers
+    }
+
+    #[test]
+    fn rules_survive_display_and_parse() {
+        for line in ["time 09:00-17:00 => https://example.com/day",
+                     "time 22:00-06:00 mon,tue,wed -02:30 => https://example.com/night",
+                     "ua iphone|android => https://example.com/app",
+                     "lang fr => https://example.com/fr",
+                     "header X-Team: sre => https://example.com/runbook"]
+        {
+            let rule = line.parse::<Rule>().unwrap();
+            assert_eq!(rule.to_string(), line);
+            assert_eq!(rule.to_string().parse::<Rule>().unwrap(), rule);
+        }
+    }
+
+    #[test]
+    fn day_ranges_are_expanded() {
+        let rule = "time 09:00-17:00 fri-mon => https://example.com".parse::<Rule>()
+                                                                    .unwrap();
+        assert_eq!(rule.to_string(),
+                   "time 09:00-17:00 fri,sat,sun,mon => https://example.com");
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        for line in ["ua iphone",
+                     "ua (( => https://example.com",
+                     "header bad name: x => https://example.com",
+                     "time 25:00-06:00 => https://example.com",
+                     "time 09:00-17:00 +24:00 => https://example.com",
+                     "weather rain => https://example.com"]
+        {
+            assert!(line.parse::<Rule>().is_err(), "{line}");
+        }
+    }
+
+    #[test]
+    fn time_windows_wrap_past_midnight() {
+        let night = "time 22:00-06:00".parse::<Condition>().unwrap();
+        let headers = HeaderMap::new();
+
+        assert!(night.matches(&headers, at(23, 30)));
+        assert!(night.matches(&headers, at(2, 0)));
+        assert!(!night.matches(&headers, at(6, 0)));
+        assert!(!night.matches(&headers, at(12, 0)));
+    }
+
+    #[test]
+    fn time_windows_use_their_offset() {
+        let morning = "time 09:00-12:00 +02:00".parse::<Condition>().unwrap();
+        let headers = HeaderMap::new();
+
+        assert!(morning.matches(&headers, at(7, 30)));
+        assert!(!morning.matches(&headers, at(10, 30)));
+    }
+
+    #[test]
+    fn refused_languages_dont_match() {
+        let french = "lang fr".parse::<Condition>().unwrap();
+        let now = at(12, 0);
+
+        assert!(french.matches(&languages("en-GB, fr-CH;q=0.8"), now));
+        assert!(!french.matches(&languages("en-GB, fr;q=0"), now));
+        assert!(!french.matches(&languages("fry"), now));
+    }
+
+    #[test]
+    fn patterns_are_stored_as_text() {
+        let rule = "ua iphone => https://example.com".parse::<Rule>().unwrap();
+        let stored = serde_json::to_value(&rule).unwrap();
+        assert_eq!(stored["condition"]["pattern"], "iphone");
+
+        let mut headers = HeaderMap::new();
+        headers.insert(USER_AGENT, HeaderValue::from_static("Mozilla/5.0 (iPhone)"));
+        let read = serde_json::from_value::<Rule>(stored).unwrap();
+        assert!(read.condition.matches(&headers, at(12, 0)));
+
+        // a broken one stored before patterns were checked still reads, and matches nothing
+        let broken = serde_json::json!({"type": "user_agent", "pattern": "(("});
+        let broken = serde_json::from_value::<Condition>(broken).unwrap();
+        assert!(!broken.matches(&headers, at(12, 0)));
+    }
+
+    #[test]
+    fn conditions_from_json_are_validated() {
+        let header = Condition::Header { name: "bad name".to_string(),
+                                         pattern: Pattern::new("x") };
+        let agent = Condition::UserAgent { pattern: Pattern::new("((") };
+
+        assert!(header.validate().is_err());
+        assert!(agent.validate().is_err());
+    }
+}
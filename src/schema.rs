@@ -0,0 +1,201 @@
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use thiserror::Error;
+use tracing::{info, warn};
+
+use crate::{database::{AppState, DatabaseError, BATCH_SIZE},
+            models::Shortlink};
+
+/// the field stored shortlinks carry their schema version in, records without one are version 0
+const VERSION_FIELD: &str = "schema_version";
+
+/// Each step upgrades a stored shortlink by one version, the first taking version 0 to 1, so a
+/// change to [`Shortlink`] that old records can't be read as needs a step here
+const MIGRATIONS: &[fn(&mut Map<String, Value>)] = &[fill_legacy_fields];
+
+/// the version shortlinks are written at
+pub const SCHEMA_VERSION: u64 = MIGRATIONS.len() as u64;
+
+/// version 0 is everything stored before there were versions, which includes links the first
+/// linkshrink wrote with an `id` and `null` for fields it hadn't set
+fn fill_legacy_fields(record: &mut Map<String, Value>) {
+    record.remove("id");
+    for (field, default) in [("hits", json!(0)),
+                             ("private", json!(false)),
+                             ("owner", json!("")),
+                             ("description", json!(""))]
+    {
+        if record.get(field).is_some_and(Value::is_null) {
+            record.insert(field.to_string(), default);
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum DecodeError {
+    #[error("Not a shortlink: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Not a JSON object")]
+    NotAnObject,
+    #[error("Invalid schema version {0}")]
+    InvalidVersion(Value),
+    #[error("Written by a newer linkshrink, at schema version {0}")]
+    NewerVersion(u64),
+}
+
+/// A stored shortlink, upgraded to the current schema
+#[derive(Debug)]
+pub struct Decoded {
+    pub shortlink: Shortlink,
+    /// it was stored at an older version, and is worth writing back
+    pub upgraded: bool,
+}
+
+/// read a stored shortlink, whatever version it was written at
+pub fn decode(serialized: &str) -> Result<Decoded, DecodeError> {
+    let Value::Object(mut record) = serde_json::from_str(serialized)? else {
+        return Err(DecodeError::NotAnObject);
+    };
+
+    let version = match record.remove(VERSION_FIELD) {
+        None => 0,
+        Some(version) => version.as_u64()
+                                .ok_or(DecodeError::InvalidVersion(version))?,
+    };
+    if version > SCHEMA_VERSION {
+        return Err(DecodeError::NewerVersion(version));
+    }
+
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(&mut record);
+    }
+
+    Ok(Decoded { shortlink: serde_json::from_value(Value::Object(record))?,
+                 upgraded: version < SCHEMA_VERSION })
+}
+
+/// a shortlink as it is stored, tagged with the current version
+pub fn encode(shortlink: &Shortlink) -> Result<String, serde_json::Error> {
+    let mut value = serde_json::to_value(shortlink)?;
+    if let Value::Object(record) = &mut value {
+        record.insert(VERSION_FIELD.to_string(), SCHEMA_VERSION.into());
+    }
+    serde_json::to_string(&value)
+}
+
+/// What checking every stored shortlink found
+#[derive(Serialize, Debug, Default)]
+pub struct CheckReport {
+    schema_version: u64,
+    records: usize,
+    /// stored at an older version, readable but not yet written back
+    outdated: usize,
+    rewritten: usize,
+    /// records that can't be read at all, left out of every listing until they are fixed
+    undecodable: Vec<Undecodable>,
+}
+
+impl CheckReport {
+    pub fn rewritten(&self) -> usize {
+        self.rewritten
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Undecodable {
+    key: String,
+    error: String,
+}
+
+/// read every stored shortlink, reporting the ones that can't be read and, with `rewrite`, writing
+/// outdated ones back at the current version
+pub async fn check(state: &AppState, rewrite: bool) -> Result<CheckReport, DatabaseError> {
+    let keys = state.scan_keys("sl::*").await?;
+    let mut report = CheckReport { schema_version: SCHEMA_VERSION,
+                                   records: keys.len(),
+                                   ..Default::default() };
+
+    for batch in keys.chunks(BATCH_SIZE) {
+        let mut outdated = vec![];
+        for (key, value) in batch.iter().zip(state.get_strings(batch).await?) {
+            // deleted since the scan
+            let Some(value) = value else {
+                report.records -= 1;
+                continue;
+            };
+
+            match decode(&value) {
+                Ok(Decoded { upgraded: false, .. }) => {}
+                Ok(Decoded { shortlink,
+                             upgraded: true, }) => outdated.push(shortlink),
+                Err(err) => {
+                    warn!(key, %err, "Stored shortlink can't be read");
+                    report.undecodable.push(Undecodable { key: key.clone(),
+                                                          error: err.to_string() });
+                }
+            }
+        }
+
+        report.outdated += outdated.len();
+        if rewrite {
+            state.put_shortlinks(&outdated).await?;
+            report.rewritten += outdated.len();
+        }
+    }
+
+    report.outdated -= report.rewritten;
+    info!(records = report.records,
+          outdated = report.outdated,
+          rewritten = report.rewritten,
+          undecodable = report.undecodable.len(),
+          "Checked stored shortlinks");
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_records_are_upgraded() {
+        let decoded = decode(r#"{"id": 7, "keyword": "old", "url": "https://old", "hits": null,
+                                 "owner": null, "private": null, "description": null}"#).unwrap();
+
+        assert!(decoded.upgraded);
+        assert_eq!(decoded.shortlink.keyword, "old");
+        assert_eq!(decoded.shortlink.url, "https://old");
+        assert_eq!(decoded.shortlink.hits, 0);
+        assert_eq!(decoded.shortlink.owner, "");
+        assert!(!decoded.shortlink.private);
+    }
+
+    #[test]
+    fn current_records_round_trip() {
+        let shortlink = Shortlink { keyword: "new".to_string(),
+                                    url: "https://new".to_string(),
+                                    hits: 3,
+                                    ..Default::default() };
+        let encoded = encode(&shortlink).unwrap();
+        let decoded = decode(&encoded).unwrap();
+
+        assert!(!decoded.upgraded);
+        assert_eq!(encode(&decoded.shortlink).unwrap(), encoded);
+    }
+
+    #[test]
+    fn newer_records_are_refused() {
+        let record = format!(r#"{{"keyword": "next", "url": "https://next", "schema_version": {}}}"#,
+                             SCHEMA_VERSION + 1);
+
+        assert!(matches!(decode(&record),
+                         Err(DecodeError::NewerVersion(version)) if version == SCHEMA_VERSION + 1));
+    }
+
+    #[test]
+    fn invalid_versions_are_refused() {
+        assert!(matches!(decode(r#"{"keyword": "x", "schema_version": "1"}"#),
+                         Err(DecodeError::InvalidVersion(_))));
+        assert!(matches!(decode("[]"), Err(DecodeError::NotAnObject)));
+    }
+}
@@ -0,0 +1,296 @@
+use axum::{extract::State,
+           http::{header::{HeaderMap, AUTHORIZATION},
+                  StatusCode},
+           response::{IntoResponse, Response},
+           Form};
+use axum_template::RenderHtml;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use chrono::{DateTime, Utc};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tracing::{error, info, instrument};
+
+use crate::{audit::{self, Actor, AuditAction},
+            auth::Identity,
+            csrf::CsrfToken,
+            database::{AppState, DatabaseError},
+            error::{error_page, AppResult},
+            permissions::Permissions};
+
+/// every token starts with this, so they are easy to spot in leaked config
+const TOKEN_PREFIX: &str = "lsk_";
+
+/// What a token may be used for, each scope includes the ones before it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenScope {
+    /// look at links
+    Read,
+    /// also create, change and delete the user's links
+    Write,
+    /// also everything an admin can do, if the user is one
+    Admin,
+}
+
+impl TokenScope {
+    pub fn allows(self, needed: TokenScope) -> bool {
+        self >= needed
+    }
+}
+
+/// A personal API token, only the sha256 of the token itself is ever stored
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiToken {
+    /// hex sha256 of the token
+    pub id: String,
+    pub name: String,
+    pub scope: TokenScope,
+    /// who the token acts as, only their name as their groups may have changed since
+    #[serde(alias = "identity", deserialize_with = "stored_user")]
+    pub user: String,
+    pub created: DateTime<Utc>,
+}
+
+/// tokens created before only the name was kept hold the whole identity
+fn stored_user<'de, D>(deserializer: D) -> Result<String, D::Error>
+    where D: Deserializer<'de>
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Stored {
+        User(String),
+        Identity(Identity),
+    }
+
+    Ok(match Stored::deserialize(deserializer)? {
+        Stored::User(user) => user,
+        Stored::Identity(identity) => identity.user,
+    })
+}
+
+impl ApiToken {
+    /// who a request with this token is, never with any groups, which can't be checked again here,
+    /// so being an admin through one doesn't carry over to tokens
+    pub fn identity(&self) -> Identity {
+        Identity { user: self.user.clone(),
+                   email: None,
+                   groups: vec![] }
+    }
+}
+
+fn token_key(id: &str) -> String {
+    format!("token::{id}")
+}
+
+fn user_tokens_key(user: &str) -> String {
+    format!("tokens::{}", user.to_lowercase())
+}
+
+fn hash(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// What the `Authorization` header of a request holds
+pub enum Bearer {
+    Missing,
+    /// unknown or revoked
+    Invalid,
+    Valid(ApiToken),
+}
+
+/// look up the token behind an `Authorization: Bearer` header
+pub async fn bearer_token(state: &AppState, headers: &HeaderMap) -> Bearer {
+    let Some((scheme, token)) = headers.get(AUTHORIZATION)
+                                       .and_then(|value| value.to_str().ok())
+                                       .and_then(|value| value.trim().split_once(' '))
+    else {
+        return Bearer::Missing;
+    };
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return Bearer::Missing;
+    }
+
+    match state.get_json::<ApiToken>(&token_key(&hash(token.trim())))
+               .await
+    {
+        Ok(Some(token)) => Bearer::Valid(token),
+        Ok(None) => Bearer::Invalid,
+        Err(err) => {
+            error!("Failed to look up API token: {err}");
+            Bearer::Invalid
+        }
+    }
+}
+
+/// a fresh token for `identity`, returned in clear this one time only
+async fn create_token(state: &AppState,
+                      identity: &Identity,
+                      name: String,
+                      scope: TokenScope)
+                      -> Result<String, DatabaseError> {
+    let mut secret = [0u8; 32];
+    OsRng.fill_bytes(&mut secret);
+    let token = format!("{TOKEN_PREFIX}{}", URL_SAFE_NO_PAD.encode(secret));
+
+    let api_token = ApiToken { id: hash(&token),
+                               name,
+                               scope,
+                               user: identity.user.clone(),
+                               created: Utc::now() };
+
+    state.set_json(&token_key(&api_token.id), &api_token)
+         .await?;
+    state.add_to_set(&user_tokens_key(&identity.user), &api_token.id)
+         .await?;
+
+    Ok(token)
+}
+
+async fn user_tokens(state: &AppState, user: &str) -> Result<Vec<ApiToken>, DatabaseError> {
+    let mut tokens = vec![];
+    for id in state.set_members(&user_tokens_key(user)).await? {
+        match state.get_json::<ApiToken>(&token_key(&id)).await? {
+            Some(token) => tokens.push(token),
+            None => error!(id, "Token listed for {user} is missing"),
+        }
+    }
+    tokens.sort_by_key(|token| token.created);
+
+    Ok(tokens)
+}
+
+/// tokens can only be managed from a browser session, a leaked token must not be able to mint more
+fn token_owner(permissions: &Permissions) -> Option<Identity> {
+    permissions.identity()
+               .filter(|_| !permissions.is_token())
+               .cloned()
+}
+
+/// only admins by name, being one through a group can't be checked again when the token is used
+fn can_create_admin_tokens(state: &AppState,
+                           permissions: &Permissions,
+                           identity: &Identity)
+                           -> bool {
+    permissions.is_admin() && state.get_config().is_admin_user(&identity.user)
+}
+
+async fn render_tokens(state: &AppState,
+                       permissions: &Permissions,
+                       csrf_token: &CsrfToken,
+                       identity: &Identity,
+                       created: Option<String>)
+                       -> AppResult<Response> {
+    let tokens = user_tokens(state, &identity.user).await?;
+
+    Ok(RenderHtml("tokens",
+                  state.get_engine(),
+                  json!({
+                      "user": identity,
+                      "admin": can_create_admin_tokens(state, permissions, identity),
+                      "tokens": tokens,
+                      "csrf_token": csrf_token,
+                      "created": created
+                  })).into_response())
+}
+
+/// the user's tokens
+#[instrument(skip(state, permissions, csrf_token))]
+pub async fn list(State(state): State<AppState>,
+                  permissions: Permissions,
+                  csrf_token: CsrfToken)
+                  -> AppResult<Response> {
+    let Some(identity) = token_owner(&permissions) else {
+        return Ok(permissions.denied(&state, "manage API tokens"));
+    };
+
+    render_tokens(&state, &permissions, &csrf_token, &identity, None).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateForm {
+    name: String,
+    scope: TokenScope,
+}
+
+#[instrument(skip(state, permissions, actor, csrf_token))]
+pub async fn create(State(state): State<AppState>,
+                    permissions: Permissions,
+                    actor: Actor,
+                    csrf_token: CsrfToken,
+                    Form(form): Form<CreateForm>)
+                    -> AppResult<Response> {
+    let Some(identity) = token_owner(&permissions) else {
+        return Ok(permissions.denied(&state, "manage API tokens"));
+    };
+
+    if form.scope == TokenScope::Admin && !can_create_admin_tokens(&state, &permissions, &identity)
+    {
+        return Ok(permissions.denied(&state, "create admin tokens"));
+    }
+
+    let name = form.name.trim().to_string();
+    let detail = format!("{name} ({:?})", form.scope);
+    let token = create_token(&state, &identity, name, form.scope).await?;
+    info!(user = identity.user, scope = ?form.scope, "Created API token");
+    audit::record(&state,
+                  actor.event(AuditAction::CreateToken, None)
+                       .with_detail(detail)).await;
+
+    render_tokens(&state, &permissions, &csrf_token, &identity, Some(token)).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RevokeForm {
+    id: String,
+}
+
+#[instrument(skip(state, permissions, actor, csrf_token))]
+pub async fn revoke(State(state): State<AppState>,
+                    permissions: Permissions,
+                    actor: Actor,
+                    csrf_token: CsrfToken,
+                    Form(form): Form<RevokeForm>)
+                    -> AppResult<Response> {
+    let Some(identity) = token_owner(&permissions) else {
+        return Ok(permissions.denied(&state, "manage API tokens"));
+    };
+
+    // only ever revoke one of the user's own tokens
+    let user_tokens = user_tokens_key(&identity.user);
+    if !state.set_members(&user_tokens).await?.contains(&form.id) {
+        return Ok(error_page(state.get_engine(),
+                             StatusCode::NOT_FOUND,
+                             "No such token".to_string()));
+    }
+
+    let name = state.get_json::<ApiToken>(&token_key(&form.id))
+                    .await?
+                    .map(|token| token.name)
+                    .unwrap_or_default();
+    state.delete_key(&token_key(&form.id)).await?;
+    state.remove_from_set(&user_tokens, &form.id).await?;
+    info!(user = identity.user, "Revoked API token");
+    audit::record(&state,
+                  actor.event(AuditAction::RevokeToken, None)
+                       .with_detail(name)).await;
+
+    render_tokens(&state, &permissions, &csrf_token, &identity, None).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_forget_the_groups_they_were_created_with() {
+        let stored = r#"{"id": "abc", "name": "ci", "scope": "admin", "created": "2024-01-01T00:00:00Z",
+                         "identity": {"user": "alice", "email": null, "groups": ["admins"]}}"#;
+        let token = serde_json::from_str::<ApiToken>(stored).unwrap();
+
+        assert_eq!(token.user, "alice");
+        assert!(token.identity().groups.is_empty());
+        assert!(!serde_json::to_string(&token).unwrap().contains("admins"));
+    }
+}
@@ -0,0 +1,298 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use url::Url;
+
+use crate::{config::Config, models::Shortlink};
+
+/// Problems with a submitted shortlink, keyed by the form field they belong to
+#[derive(Debug, Default, Serialize)]
+pub struct ValidationErrors(BTreeMap<&'static str, String>);
+
+impl ValidationErrors {
+    /// keeps the first problem found for each field
+    pub fn add(&mut self, field: &'static str, message: impl Into<String>) {
+        self.0.entry(field).or_insert_with(|| message.into());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// the characters keywords may use besides ASCII letters and digits
+const KEYWORD_PUNCTUATION: &str = "._~-";
+
+/// check a keyword can be reached through `/:keyword`
+pub fn validate_keyword(config: &Config, keyword: &str) -> Result<(), String> {
+    if keyword.trim().is_empty() {
+        return Err("A keyword is required".to_string());
+    }
+
+    if keyword.ends_with('+') {
+        return Err("Keywords can't end with +, that's used to preview links".to_string());
+    }
+
+    // pages link to `/{keyword}` and sticky links set a cookie for that path, so it has to be a
+    // single path segment that needs no escaping and that browsers don't resolve like `..`
+    if !keyword.chars()
+               .all(|c| c.is_ascii_alphanumeric() || KEYWORD_PUNCTUATION.contains(c))
+    {
+        return Err("Keywords can only use letters, digits and . _ ~ -".to_string());
+    }
+    if keyword.chars().all(|c| c == '.') {
+        return Err("Keywords can't be only dots".to_string());
+    }
+
+    if config.reserved_keywords
+             .iter()
+             .any(|reserved| reserved.eq_ignore_ascii_case(keyword))
+    {
+        return Err(format!("{keyword:?} is reserved"));
+    }
+
+    if let Some(pattern) = config.forbidden_keyword_patterns
+                                 .iter()
+                                 .find(|pattern| pattern.is_match(keyword))
+    {
+        return Err(format!("{keyword:?} matches the forbidden pattern {pattern}"));
+    }
+
+    Ok(())
+}
+
+/// check a shortlink's keyword, its rules, and every url it could send someone to, writing the urls
+/// back the way they are parsed so non-ASCII ones can still go in a `Location` header
+pub fn validate_shortlink(config: &Config,
+                          request_host: Option<&str>,
+                          shortlink: &mut Shortlink,
+                          errors: &mut ValidationErrors) {
+    if let Err(err) = validate_keyword(config, &shortlink.keyword) {
+        errors.add("keyword", err);
+    }
+    normalize_url(config, request_host, &mut shortlink.url, "url", errors);
+    for destination in &mut shortlink.destinations {
+        normalize_url(config,
+                      request_host,
+                      &mut destination.url,
+                      "destinations",
+                      errors);
+    }
+    for rule in &mut shortlink.rules {
+        if let Err(err) = rule.condition.validate() {
+            errors.add("rules", err);
+        }
+        normalize_url(config, request_host, &mut rule.url, "rules", errors);
+    }
+}
+
+fn normalize_url(config: &Config,
+                 request_host: Option<&str>,
+                 url: &mut String,
+                 field: &'static str,
+                 errors: &mut ValidationErrors) {
+    match validate_url(config, request_host, url) {
+        Ok(parsed) => *url = parsed.into(),
+        Err(err) => errors.add(field, err),
+    }
+}
+
+/// the literal first path segment of each route, which `/:keyword` can never reach
+pub fn reserved_by_routes<'a>(paths: impl Iterator<Item = &'a str>) -> Vec<String> {
+    paths.filter_map(|path| path.trim_start_matches('/').split('/').next())
+         .filter(|segment| !segment.is_empty() && !segment.starts_with([':', '*']))
+         .map(str::to_lowercase)
+         .collect()
+}
+
+/// check a destination url is something we are willing to redirect to
+///
+/// `request_host` is the `Host` the form was submitted to, so links back to linkshrink itself are
+/// caught even when `public_hosts` isn't configured
+pub fn validate_url(config: &Config, request_host: Option<&str>, url: &str) -> Result<Url, String> {
+    let url = url.trim();
+    if url.is_empty() {
+        return Err("A url is required".to_string());
+    }
+
+    let parsed = Url::parse(url).map_err(|err| match err {
+                     url::ParseError::RelativeUrlWithoutBase if !url.starts_with('/') => {
+                         format!("{url:?} must be an absolute url, e.g. https://{url}")
+                     }
+                     url::ParseError::RelativeUrlWithoutBase => {
+                         format!("{url:?} must be an absolute url")
+                     }
+                     err => format!("{url:?} is not a valid url: {err}"),
+                 })?;
+
+    if !config.allowed_schemes
+              .iter()
+              .any(|scheme| scheme == parsed.scheme())
+    {
+        return Err(format!("The {:?} scheme is not allowed, use one of: {}",
+                           parsed.scheme(),
+                           config.allowed_schemes.join(", ")));
+    }
+
+    if matches!(parsed.scheme(), "http" | "https") && parsed.host_str().is_none() {
+        return Err(format!("{url:?} is missing a host"));
+    }
+
+    if is_self_reference(config, request_host, &parsed) {
+        return Err("Links back to this shortener would redirect forever".to_string());
+    }
+
+    if let Some(violation) = domain_violation(config, &parsed) {
+        return Err(violation);
+    }
+
+    Ok(parsed)
+}
+
+/// check a url against the configured domain allow and deny lists
+pub fn domain_violation(config: &Config, url: &Url) -> Option<String> {
+    let host = url.host_str()?.trim_end_matches('.').to_lowercase();
+
+    if let Some(denied) = config.denied_domains
+                                .iter()
+                                .find(|pattern| domain_matches(pattern, &host))
+    {
+        return Some(format!("Links to {host} are not allowed (matches {denied})"));
+    }
+
+    if !config.allowed_domains.is_empty()
+       && !config.allowed_domains
+                 .iter()
+                 .any(|pattern| domain_matches(pattern, &host))
+    {
+        return Some(format!("Links to {host} are not allowed, only to {}",
+                            config.allowed_domains.join(", ")));
+    }
+
+    None
+}
+
+/// `example.com` matches only itself, `*.example.com` matches it and every subdomain
+fn domain_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => {
+            host == domain
+            || host.strip_suffix(domain)
+                   .is_some_and(|subdomain| subdomain.ends_with('.'))
+        }
+        None => host == pattern,
+    }
+}
+
+fn is_self_reference(config: &Config, request_host: Option<&str>, url: &Url) -> bool {
+    let Some(host) = url.host_str().map(str::to_lowercase) else {
+        return false;
+    };
+
+    if config.public_hosts.contains(&host) {
+        return true;
+    }
+
+    // compare the full authority, localhost:8080 and localhost:3000 are different services
+    request_host.map(str::to_lowercase)
+                .is_some_and(|request_host| {
+                    let authority = match url.port() {
+                        Some(port) => format!("{host}:{port}"),
+                        None => host.clone(),
+                    };
+                    request_host == authority
+                })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        Config { allowed_schemes: vec!["http".to_string(), "https".to_string()],
+                 public_hosts: vec![],
+                 reserved_keywords: vec!["edit".to_string()],
+                 forbidden_keyword_patterns: vec![],
+                 allowed_domains: vec![],
+                 denied_domains: vec![],
+                 ..Config::from_env() }
+    }
+
+    #[test]
+    fn wildcards_match_subdomains_not_suffixes() {
+        assert!(domain_matches("example.com", "example.com"));
+        assert!(!domain_matches("example.com", "www.example.com"));
+        assert!(domain_matches("*.example.com", "example.com"));
+        assert!(domain_matches("*.example.com", "a.b.example.com"));
+        assert!(!domain_matches("*.example.com", "badexample.com"));
+        assert!(!domain_matches("*.example.com", "example.com.evil"));
+    }
+
+    #[test]
+    fn domain_lists_are_applied() {
+        let config = Config { allowed_domains: vec!["*.example.com".to_string()],
+                              denied_domains: vec!["bad.example.com".to_string()],
+                              ..config() };
+        let url = |url| Url::parse(url).unwrap();
+
+        assert!(domain_violation(&config, &url("https://docs.example.com/x")).is_none());
+        assert!(domain_violation(&config, &url("https://BAD.example.com./x")).is_some());
+        assert!(domain_violation(&config, &url("https://badexample.com")).is_some());
+    }
+
+    #[test]
+    fn links_back_to_us_are_refused() {
+        let config = Config { public_hosts: vec!["go".to_string()],
+                              ..config() };
+
+        assert!(validate_url(&config, None, "https://go/docs").is_err());
+        assert!(validate_url(&config, Some("localhost:8080"), "http://LOCALHOST:8080/x").is_err());
+        assert!(validate_url(&config, Some("localhost:8080"), "http://localhost:3000/x").is_ok());
+        assert!(validate_url(&config,
+                             Some("links.example.com"),
+                             "https://links.example.com/x").is_err());
+        assert!(validate_url(&config, Some("links.example.com"), "https://example.com/x").is_ok());
+    }
+
+    #[test]
+    fn only_allowed_schemes() {
+        let config = config();
+
+        assert!(validate_url(&config, None, " https://example.com ").is_ok());
+        for url in ["javascript:alert(1)",
+                    "data:text/html,hi",
+                    "ftp://example.com",
+                    "example.com",
+                    "/relative",
+                    "https://",
+                    ""]
+        {
+            assert!(validate_url(&config, None, url).is_err(), "{url:?}");
+        }
+    }
+
+    #[test]
+    fn routes_reserve_their_first_segment() {
+        let reserved = reserved_by_routes(["/",
+                                           "/links",
+                                           "/edit/:keyword",
+                                           "/:keyword",
+                                           "/*rest",
+                                           "/API/v1/links"].into_iter());
+
+        assert_eq!(reserved, ["links", "edit", "api"]);
+    }
+
+    #[test]
+    fn keywords_fit_in_a_path_segment() {
+        let config = config();
+
+        for keyword in ["docs", "Team-Wiki", "v1.2", "a_b~c"] {
+            assert!(validate_keyword(&config, keyword).is_ok(), "{keyword}");
+        }
+        for keyword in ["", " ", "a/b", "a?b", "a#b", "a;b", "a%3Fb", "a b", "Straße", "docs+",
+                        ".", "..", "EDIT"]
+        {
+            assert!(validate_keyword(&config, keyword).is_err(), "{keyword:?}");
+        }
+    }
+}